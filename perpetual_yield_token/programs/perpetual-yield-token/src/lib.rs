@@ -1,6 +1,10 @@
+#![allow(unexpected_cfgs)]
+
 use anchor_lang::prelude::*;
 use anchor_lang::solana_program::clock::Clock;
-use anchor_spl::token::{self, Token, TokenAccount, Transfer, Mint};
+use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+use anchor_lang::solana_program::program::invoke_signed;
+use anchor_spl::token::{self, Burn, Token, TokenAccount, Transfer, Mint};
 use std::convert::TryInto;
 
 declare_id!("FkQpVUX5iRw5Gft6Co1iKJDnufEA3FYBsGJS87RaT4nf");
@@ -13,12 +17,66 @@ const THIRTY_DAYS: i64 = 30 * SECONDS_IN_DAY;
 const NINETY_DAYS: i64 = 90 * SECONDS_IN_DAY;
 /// Bonus multiplier for LP stakers (e.g. 110 means +10% bonus).
 const LP_BONUS_MULTIPLIER: u64 = 110;
+/// Maximum number of deposit lots tracked per position; the newest lots are merged beyond this.
+const MAX_STAKE_LOTS: usize = 8;
+/// Maximum number of breakpoints in the time-multiplier schedule.
+const MAX_MULTIPLIER_BREAKPOINTS: usize = 8;
+/// Maximum number of trade-volume rebate tiers.
+const MAX_REBATE_TIERS: usize = 8;
+/// Largest rebate (in percent) any tier may grant.
+const MAX_REBATE_PERCENT: u64 = 25;
+/// Vote-escrow lock ends are rounded down to whole weeks.
+const WEEK: i64 = 7 * SECONDS_IN_DAY;
+/// Longest lock governance may allow.
+const MAX_VE_LOCK: i64 = 4 * 365 * SECONDS_IN_DAY;
+/// Length of the weekly slope-change ring; must exceed the weeks in `MAX_VE_LOCK`.
+const VE_SCHEDULE_WEEKS: usize = 210;
+/// Fixed-point scale for vote-escrow slopes and bias.
+const VE_PRECISION: u128 = REWARD_MULTIPLIER as u128;
+/// Default reward boost (in percent) for stake locked for the full `ve_max_lock`.
+const DEFAULT_VE_MAX_BOOST: u64 = 100;
+/// Largest reward boost governance may configure.
+const MAX_VE_BOOST: u64 = 150;
+/// Fixed-point scale for `acc_reward_per_weight`.
+const GAUGE_PRECISION: u128 = 1_000_000_000_000_000_000;
+/// Most gauges a global state may have; `checkpoint_gauges` must fit all of them, with
+/// their pools, in one transaction.
+const MAX_GAUGES: u64 = 12;
+/// Number of checkpoints kept per position. Beyond this a balance-only change is dropped
+/// first, so the lock and delegate history votes read from is kept longest.
+const MAX_POSITION_CHECKPOINTS: usize = 8;
+/// Number of global stake checkpoints in one `StakeHistory` page.
+const STAKE_HISTORY_PAGE_LEN: usize = 64;
+/// Longest `proposal_data` that fits in a `Proposal` account.
+const MAX_PROPOSAL_DATA_LEN: usize = 400;
+/// Longest memo a treasury spend may carry.
+const MAX_SPEND_MEMO_LEN: usize = 64;
+/// Largest signer set a governance multisig may have.
+const MAX_MULTISIG_SIGNERS: usize = 10;
+/// Limits on the instruction a governance action may carry.
+const MAX_ACTION_ACCOUNTS: usize = 16;
+const MAX_ACTION_DATA_LEN: usize = 256;
+/// Default trailing window used to derive the utilization multiplier on-chain.
+const DEFAULT_UTILIZATION_WINDOW: i64 = 7 * SECONDS_IN_DAY;
+/// Default fee yield (in basis points of total stake per window) that maps to a 100 multiplier.
+const DEFAULT_TARGET_FEE_YIELD_BPS: u64 = 10;
+/// Default period over which each fee deposit is streamed to stakers.
+const DEFAULT_REWARD_DURATION: i64 = 7 * SECONDS_IN_DAY;
+/// Hard ceiling for the utilization multiplier, derived or manual, so claims can never be
+/// scaled above what was allocated.
+const MAX_DERIVED_UTILIZATION: u64 = 100;
+/// Largest governance adjustment (in multiplier points) applied on top of the derived value.
+const MAX_UTILIZATION_ADJUSTMENT: i64 = 20;
+/// Delay before loosened parameter limits take effect, so they cannot be relaxed and
+/// exploited in one transaction.
+const PARAMETER_LIMITS_DELAY: i64 = 2 * SECONDS_IN_DAY;
 
 #[program]
 pub mod perpetual_yield_token {
     use super::*;
 
     /// Initialize the global state.
+    #[allow(clippy::too_many_arguments)]
     pub fn initialize(
         ctx: Context<Initialize>,
         governance: Pubkey,
@@ -29,10 +87,24 @@ pub mod perpetual_yield_token {
         insurance_fee_percent: u64,
         utilization_multiplier: u64,
     ) -> Result<()> {
+        let params = ProtocolParameters {
+            cooldown_period,
+            early_withdrawal_penalty,
+            min_withdraw_interval,
+            min_claim_delay,
+            insurance_fee_percent,
+            utilization_multiplier,
+        };
+        params.validate(0)?;
         let state = &mut ctx.accounts.global_state;
         state.total_staked = 0;
         state.acc_reward_per_share = 0;
         state.token_mint = ctx.accounts.token_mint.key();
+        state.reward_vault = ctx.accounts.reward_vault.key();
+        state.reward_remainder = 0;
+        state.staking_vault = ctx.accounts.staking_vault.key();
+        state.gauge_count = 0;
+        state.gauge_epoch = 0;
         state.owner = ctx.accounts.owner.key();
         state.governance = governance;
         state.cooldown_period = cooldown_period;
@@ -43,15 +115,77 @@ pub mod perpetual_yield_token {
         state.utilization_multiplier = utilization_multiplier;
         state.last_fee_deposit_time = 0;
         state.insurance_fund = 0;
-        state.pool_info = [
-            PoolInfo { lockup_period: 7 * SECONDS_IN_DAY, apr_multiplier: 100, transaction_fee: 50 },
-            PoolInfo { lockup_period: 14 * SECONDS_IN_DAY, apr_multiplier: 110, transaction_fee: 75 },
-            PoolInfo { lockup_period: 30 * SECONDS_IN_DAY, apr_multiplier: 120, transaction_fee: 100 },
+        state.utilization_mode = UtilizationMode::Manual;
+        state.utilization_window = DEFAULT_UTILIZATION_WINDOW;
+        state.utilization_window_start = Clock::get()?.unix_timestamp;
+        state.window_fee_total = 0;
+        state.trailing_fee_total = 0;
+        state.target_fee_yield_bps = DEFAULT_TARGET_FEE_YIELD_BPS;
+        state.min_utilization_multiplier = MAX_DERIVED_UTILIZATION / 2;
+        state.max_utilization_multiplier = MAX_DERIVED_UTILIZATION;
+        state.utilization_adjustment = 0;
+        state.lp_total_staked = 0;
+        state.total_reward_liabilities = 0;
+        state.bonus_budget = 0;
+        state.total_owed = 0;
+        state.owed_reserve = 0;
+        state.reward_duration = DEFAULT_REWARD_DURATION;
+        state.reward_rate = 0;
+        state.period_finish = 0;
+        state.last_update_time = Clock::get()?.unix_timestamp;
+        state.warmup_period = 0;
+        state.treasury = Pubkey::default();
+        state.total_reward_shares = 0;
+        state.pool_count = 0;
+        state.time_multiplier_schedule = vec![
+            MultiplierBreakpoint { min_duration: 0, multiplier: 100 },
+            MultiplierBreakpoint { min_duration: THIRTY_DAYS, multiplier: 120 },
+            MultiplierBreakpoint { min_duration: NINETY_DAYS, multiplier: 150 },
         ];
+        state.interpolate_time_multiplier = false;
+        state.ve_max_lock = MAX_VE_LOCK;
+        state.ve_max_boost = DEFAULT_VE_MAX_BOOST;
+        state.lp_acc_reward_per_share = 0;
+        state.acc_reward_per_weight = 0;
+        state.total_gauge_weight = 0;
+        state.lp_gauge_weight = 0;
+        state.stake_history_page = 0;
+        state.last_stake_checkpoint = GlobalCheckpoint::default();
+        state.proposal_config = ProposalConfig {
+            deposit: 0,
+            voting_delay: SECONDS_IN_DAY,
+            voting_period: 7 * SECONDS_IN_DAY,
+            execution_window: 7 * SECONDS_IN_DAY,
+            quorum_bps: 400,
+            slash_quorum_bps: 100,
+        };
+        state.treasury_fee_bps = 0;
+        state.pending_owner = Pubkey::default();
+        state.pending_governance = Pubkey::default();
+        state.parameter_limits = ParameterLimits::default();
+        state.pending_parameter_limits = None;
+        state.parameter_limits_effective_at = 0;
+        state.proposal_deposit_vault = Pubkey::default();
+        let rebate_config = &mut ctx.accounts.rebate_config;
+        rebate_config.global_state = state.key();
+        rebate_config.tiers = RebateConfig::default_tiers();
+        rebate_config.bump = ctx.bumps.rebate_config;
+        emit!(Initialized {
+            global_state: state.key(),
+            owner: state.owner,
+            governance,
+            token_mint: state.token_mint,
+            parameters: params,
+        });
+        emit!(RebateTiersUpdated {
+            rebate_config: rebate_config.key(),
+            tiers: rebate_config.tiers.clone(),
+        });
         Ok(())
     }
 
     /// Update protocol parameters.
+    #[allow(clippy::too_many_arguments)]
     pub fn update_parameters(
         ctx: Context<UpdateParameters>,
         cooldown_period: i64,
@@ -60,44 +194,542 @@ pub mod perpetual_yield_token {
         min_claim_delay: i64,
         insurance_fee_percent: u64,
         utilization_multiplier: u64,
-        pool_info: [PoolInfo; 3],
     ) -> Result<()> {
         let state = &mut ctx.accounts.global_state;
+        let params = ProtocolParameters {
+            cooldown_period,
+            early_withdrawal_penalty,
+            min_withdraw_interval,
+            min_claim_delay,
+            insurance_fee_percent,
+            utilization_multiplier,
+        };
+        params.validate(state.treasury_fee_bps)?;
+        apply_pending_limits(state, Clock::get()?.unix_timestamp);
+        let current = ProtocolParameters::current(state);
+        // In on-chain mode the multiplier is not applied, so it is not subject to the limits.
+        let applied = if state.utilization_mode == UtilizationMode::Manual {
+            params
+        } else {
+            ProtocolParameters { utilization_multiplier: current.utilization_multiplier, ..params }
+        };
+        state.parameter_limits.check(&current, &applied)?;
         state.cooldown_period = cooldown_period;
         state.early_withdrawal_penalty = early_withdrawal_penalty;
         state.min_withdraw_interval = min_withdraw_interval;
         state.min_claim_delay = min_claim_delay;
         state.insurance_fee_percent = insurance_fee_percent;
-        state.utilization_multiplier = utilization_multiplier;
-        state.pool_info = pool_info;
+        // In on-chain mode the multiplier is owned by `refresh_utilization`.
+        if state.utilization_mode == UtilizationMode::Manual {
+            state.utilization_multiplier = utilization_multiplier;
+        }
+        emit!(ParametersUpdated {
+            old: current,
+            new: ProtocolParameters::current(state),
+        });
+        Ok(())
+    }
+
+    /// Create a new staking pool with the next free pool id.
+    pub fn create_pool(ctx: Context<CreatePool>, info: PoolInfo) -> Result<()> {
+        info.validate(&ctx.accounts.global_state)?;
+        let clock = Clock::get()?;
+        let state = &mut ctx.accounts.global_state;
+        update_reward_accrual(state, clock.unix_timestamp)?;
+        let pool = &mut ctx.accounts.pool;
+        pool.global_state = state.key();
+        pool.pool_id = state.pool_count;
+        pool.info = info;
+        pool.total_staked = 0;
+        pool.total_reward_shares = 0;
+        pool.acc_reward_per_share = 0;
+        pool.last_global_acc = state.acc_reward_per_share;
+        pool.gauge_weight = 0;
+        pool.last_acc_per_weight = state.acc_reward_per_weight;
+        pool.retired = false;
+        pool.bump = ctx.bumps.pool;
+        state.pool_count = state.pool_count.checked_add(1).ok_or(CustomError::MathOverflow)?;
+        emit!(PoolCreated {
+            pool: pool.key(),
+            pool_id: pool.pool_id,
+            info: pool.info.clone(),
+        });
+        Ok(())
+    }
+
+    /// Tune an existing pool. A new `apr_multiplier` applies to each position the next
+    /// time it is touched.
+    pub fn update_pool(ctx: Context<UpdatePool>, info: PoolInfo) -> Result<()> {
+        info.validate(&ctx.accounts.global_state)?;
+        ctx.accounts.pool.info = info.clone();
+        emit!(PoolUpdated {
+            pool: ctx.accounts.pool.key(),
+            info,
+        });
+        Ok(())
+    }
+
+    /// Sunset a pool. It stops taking new stakes and its lockup penalty is waived, so
+    /// existing positions can unstake or `migrate_position` out freely.
+    pub fn retire_pool(ctx: Context<UpdatePool>) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        require!(!pool.retired, CustomError::PoolRetired);
+        pool.retired = true;
+        emit!(PoolRetired { pool: pool.key() });
         Ok(())
     }
 
-    /// Update the utilization multiplier.
+    /// Update the utilization multiplier (manual mode only), up to `MAX_DERIVED_UTILIZATION`.
     pub fn update_utilization(ctx: Context<UpdateParameters>, utilization_multiplier: u64) -> Result<()> {
         let state = &mut ctx.accounts.global_state;
+        require!(
+            state.utilization_mode == UtilizationMode::Manual,
+            CustomError::UtilizationDerivedOnChain
+        );
+        check_parameter(
+            utilization_multiplier > 0 && utilization_multiplier <= MAX_DERIVED_UTILIZATION,
+            "utilization_multiplier",
+        )?;
+        apply_pending_limits(state, Clock::get()?.unix_timestamp);
+        check_change(
+            "utilization_multiplier",
+            state.utilization_multiplier as i128,
+            utilization_multiplier as i128,
+            state.parameter_limits.max_utilization_change,
+        )?;
+        let old = ProtocolParameters::current(state);
         state.utilization_multiplier = utilization_multiplier;
+        emit!(ParametersUpdated {
+            old,
+            new: ProtocolParameters::current(state),
+        });
         Ok(())
     }
 
-    /// Stake $PYT tokens into a chosen pool (0 = Low, 1 = Medium, 2 = High).
-    pub fn stake(ctx: Context<Stake>, amount: u64, pool_type: u8) -> Result<()> {
+    /// Set how far a single `update_parameters` call may move each parameter. Tighter
+    /// limits apply at once; loosening any field is queued for `PARAMETER_LIMITS_DELAY`.
+    pub fn set_parameter_limits(ctx: Context<UpdateParameters>, limits: ParameterLimits) -> Result<()> {
         let clock = Clock::get()?;
         let state = &mut ctx.accounts.global_state;
-        let user = &mut ctx.accounts.user_stake;
-        require!(pool_type < 3, CustomError::InvalidPoolType);
-
-        if user.staked_amount > 0 {
-            let accumulated = (user.staked_amount as u128)
-                .checked_mul(state.acc_reward_per_share as u128)
-                .ok_or(CustomError::MathOverflow)?
-                / REWARD_MULTIPLIER as u128;
-            let pending = accumulated.checked_sub(user.reward_debt as u128)
-                .ok_or(CustomError::MathOverflow)?;
-            user.pending_rewards = user.pending_rewards
-                .checked_add(pending as u64)
+        apply_pending_limits(state, clock.unix_timestamp);
+        let effective_at = if limits.loosens(&state.parameter_limits) {
+            let effective_at = clock.unix_timestamp
+                .checked_add(PARAMETER_LIMITS_DELAY)
                 .ok_or(CustomError::MathOverflow)?;
+            state.pending_parameter_limits = Some(limits);
+            state.parameter_limits_effective_at = effective_at;
+            effective_at
+        } else {
+            state.parameter_limits = limits;
+            state.pending_parameter_limits = None;
+            clock.unix_timestamp
+        };
+        emit!(ParameterLimitsUpdated { limits, effective_at });
+        Ok(())
+    }
+
+    /// Set the period over which future fee deposits are streamed (0 = allocate instantly).
+    pub fn set_reward_duration(ctx: Context<UpdateParameters>, reward_duration: i64) -> Result<()> {
+        require!(reward_duration >= 0, CustomError::InvalidRewardDuration);
+        let clock = Clock::get()?;
+        let state = &mut ctx.accounts.global_state;
+        // Settle the running stream first; its rate and finish time are left untouched.
+        update_reward_accrual(state, clock.unix_timestamp)?;
+        state.reward_duration = reward_duration;
+        emit!(RewardDurationUpdated { reward_duration });
+        Ok(())
+    }
+
+    /// Hand governance to an m-of-n signer set. `GlobalState.governance` becomes the
+    /// multisig's signer PDA, so governance instructions can then only run through an
+    /// approved `execute_governance_action`. Instructions that pay for new accounts need
+    /// the signer PDA to hold SOL.
+    pub fn create_multisig(ctx: Context<CreateMultisig>, signers: Vec<Pubkey>, threshold: u8) -> Result<()> {
+        validate_multisig(&signers, threshold)?;
+        let multisig = &mut ctx.accounts.multisig;
+        multisig.global_state = ctx.accounts.global_state.key();
+        multisig.signers = signers;
+        multisig.threshold = threshold;
+        multisig.owner_set_seqno = 0;
+        multisig.signer_bump = ctx.bumps.multisig_signer;
+        multisig.bump = ctx.bumps.multisig;
+        ctx.accounts.global_state.governance = ctx.accounts.multisig_signer.key();
+        emit!(MultisigConfigUpdated {
+            multisig: multisig.key(),
+            signers: multisig.signers.clone(),
+            threshold,
+            owner_set_seqno: 0,
+        });
+        Ok(())
+    }
+
+    /// Change the multisig's signers and threshold. Only callable by the multisig itself,
+    /// so the change needs the current threshold. Pending actions become stale.
+    pub fn set_multisig_config(ctx: Context<MultisigAuth>, signers: Vec<Pubkey>, threshold: u8) -> Result<()> {
+        validate_multisig(&signers, threshold)?;
+        let multisig = &mut ctx.accounts.multisig;
+        multisig.signers = signers;
+        multisig.threshold = threshold;
+        multisig.owner_set_seqno = multisig.owner_set_seqno.checked_add(1).ok_or(CustomError::MathOverflow)?;
+        emit!(MultisigConfigUpdated {
+            multisig: multisig.key(),
+            signers: multisig.signers.clone(),
+            threshold,
+            owner_set_seqno: multisig.owner_set_seqno,
+        });
+        Ok(())
+    }
+
+    /// Propose an instruction on this program to be signed by the multisig. The proposer
+    /// must be a multisig signer and counts as the first approval.
+    pub fn propose_governance_action(
+        ctx: Context<ProposeGovernanceAction>,
+        accounts: Vec<ActionAccount>,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        require!(
+            accounts.len() <= MAX_ACTION_ACCOUNTS && data.len() <= MAX_ACTION_DATA_LEN,
+            CustomError::GovernanceActionTooLarge
+        );
+        let multisig = &ctx.accounts.multisig;
+        let index = multisig.signer_index(&ctx.accounts.proposer.key())?;
+        let action = &mut ctx.accounts.action;
+        action.multisig = multisig.key();
+        action.accounts = accounts;
+        action.data = data;
+        action.approvals = vec![false; multisig.signers.len()];
+        action.approvals[index] = true;
+        action.owner_set_seqno = multisig.owner_set_seqno;
+        action.executed = false;
+        emit!(GovernanceActionProposed {
+            action: action.key(),
+            multisig: action.multisig,
+            proposer: ctx.accounts.proposer.key(),
+        });
+        Ok(())
+    }
+
+    /// Approve a pending governance action as one of the multisig signers.
+    pub fn approve_governance_action(ctx: Context<ApproveGovernanceAction>) -> Result<()> {
+        let multisig = &ctx.accounts.multisig;
+        let action = &mut ctx.accounts.action;
+        require!(action.owner_set_seqno == multisig.owner_set_seqno, CustomError::StaleGovernanceAction);
+        let index = multisig.signer_index(&ctx.accounts.signer.key())?;
+        action.approvals[index] = true;
+        emit!(GovernanceActionApproved {
+            action: action.key(),
+            signer: ctx.accounts.signer.key(),
+            approvals: action.approvals.iter().filter(|approved| **approved).count() as u8,
+        });
+        Ok(())
+    }
+
+    /// Run an action that has reached the threshold, signing as the multisig's signer PDA.
+    /// Pass every account the action references, plus this program, in `remaining_accounts`.
+    pub fn execute_governance_action<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ExecuteGovernanceAction<'info>>,
+    ) -> Result<()> {
+        let multisig = &ctx.accounts.multisig;
+        let action = &mut ctx.accounts.action;
+        require!(!action.executed, CustomError::ProposalAlreadyExecuted);
+        require!(action.owner_set_seqno == multisig.owner_set_seqno, CustomError::StaleGovernanceAction);
+        let approvals = action.approvals.iter().filter(|approved| **approved).count();
+        require!(approvals >= multisig.threshold as usize, CustomError::NotEnoughApprovals);
+        action.executed = true;
+
+        let signer_key = ctx.accounts.multisig_signer.key();
+        let instruction = Instruction {
+            program_id: crate::ID,
+            accounts: action.accounts
+                .iter()
+                .map(|account| AccountMeta {
+                    pubkey: account.pubkey,
+                    is_signer: account.is_signer || account.pubkey == signer_key,
+                    is_writable: account.is_writable,
+                })
+                .collect(),
+            data: action.data.clone(),
+        };
+        let multisig_key = multisig.key();
+        invoke_signed(
+            &instruction,
+            ctx.remaining_accounts,
+            &[&[b"multisig_signer", multisig_key.as_ref(), &[multisig.signer_bump]]],
+        )?;
+        emit!(GovernanceActionExecuted {
+            action: action.key(),
+            multisig: multisig_key,
+        });
+        Ok(())
+    }
+
+    /// Create the program-owned treasury vault and point `treasury` at it. Tokens leave it
+    /// only through `treasury_spend` on an executed proposal.
+    pub fn initialize_treasury(ctx: Context<InitializeTreasury>) -> Result<()> {
+        ctx.accounts.global_state.treasury = ctx.accounts.treasury_vault.key();
+        emit!(TreasuryInitialized {
+            global_state: ctx.accounts.global_state.key(),
+            treasury: ctx.accounts.treasury_vault.key(),
+        });
+        Ok(())
+    }
+
+    /// Set the protocol cut of fee deposits sent to the treasury.
+    pub fn set_treasury_fee(ctx: Context<UpdateParameters>, treasury_fee_bps: u64) -> Result<()> {
+        let state = &mut ctx.accounts.global_state;
+        require!(
+            treasury_fee_bps.checked_add(state.insurance_fee_percent).is_some_and(|total| total <= 10_000),
+            CustomError::InvalidTreasuryFee
+        );
+        // Fee deposits must name the treasury vault once a cut is taken.
+        require!(
+            treasury_fee_bps == 0 || state.treasury != Pubkey::default(),
+            CustomError::TreasuryNotInitialized
+        );
+        state.treasury_fee_bps = treasury_fee_bps;
+        emit!(TreasuryFeeUpdated { treasury_fee_bps });
+        Ok(())
+    }
+
+    /// Nominate a new governance key. It takes over only once it signs
+    /// `accept_governance_transfer`; nominating the default key cancels.
+    pub fn propose_governance_transfer(ctx: Context<UpdateParameters>, new_governance: Pubkey) -> Result<()> {
+        let state = &mut ctx.accounts.global_state;
+        state.pending_governance = new_governance;
+        emit!(AuthorityTransferProposed {
+            role: AuthorityRole::Governance,
+            current: state.governance,
+            pending: new_governance,
+        });
+        Ok(())
+    }
+
+    /// Complete a governance rotation as the nominated key.
+    pub fn accept_governance_transfer(ctx: Context<AcceptGovernanceTransfer>) -> Result<()> {
+        let state = &mut ctx.accounts.global_state;
+        require!(
+            state.pending_governance == ctx.accounts.new_governance.key(),
+            CustomError::NotPendingAuthority
+        );
+        let previous = state.governance;
+        state.governance = ctx.accounts.new_governance.key();
+        state.pending_governance = Pubkey::default();
+        emit!(AuthorityTransferred {
+            role: AuthorityRole::Governance,
+            previous,
+            current: state.governance,
+        });
+        Ok(())
+    }
+
+    /// Nominate a new owner key. It takes over only once it signs `accept_owner_transfer`;
+    /// nominating the default key cancels.
+    pub fn propose_owner_transfer(ctx: Context<OwnerOnly>, new_owner: Pubkey) -> Result<()> {
+        let state = &mut ctx.accounts.global_state;
+        state.pending_owner = new_owner;
+        emit!(AuthorityTransferProposed {
+            role: AuthorityRole::Owner,
+            current: state.owner,
+            pending: new_owner,
+        });
+        Ok(())
+    }
+
+    /// Complete an owner rotation as the nominated key.
+    pub fn accept_owner_transfer(ctx: Context<AcceptOwnerTransfer>) -> Result<()> {
+        let state = &mut ctx.accounts.global_state;
+        require!(
+            state.pending_owner == ctx.accounts.new_owner.key(),
+            CustomError::NotPendingAuthority
+        );
+        let previous = state.owner;
+        state.owner = ctx.accounts.new_owner.key();
+        state.pending_owner = Pubkey::default();
+        emit!(AuthorityTransferred {
+            role: AuthorityRole::Owner,
+            previous,
+            current: state.owner,
+        });
+        Ok(())
+    }
+
+    /// Create the rebate tier account, seeded with the original 10k/100k/1M schedule.
+    /// `initialize` already creates it; this is for global states that predate that.
+    pub fn initialize_rebate_config(ctx: Context<InitializeRebateConfig>) -> Result<()> {
+        let config = &mut ctx.accounts.rebate_config;
+        config.global_state = ctx.accounts.global_state.key();
+        config.tiers = RebateConfig::default_tiers();
+        config.bump = ctx.bumps.rebate_config;
+        emit!(RebateTiersUpdated {
+            rebate_config: config.key(),
+            tiers: config.tiers.clone(),
+        });
+        Ok(())
+    }
+
+    /// Replace the trade-volume rebate tiers.
+    pub fn set_rebate_tiers(ctx: Context<SetRebateTiers>, tiers: Vec<RebateTier>) -> Result<()> {
+        validate_rebate_tiers(&tiers)?;
+        ctx.accounts.rebate_config.tiers = tiers.clone();
+        emit!(RebateTiersUpdated {
+            rebate_config: ctx.accounts.rebate_config.key(),
+            tiers,
+        });
+        Ok(())
+    }
+
+    /// Create the global vote-escrow curve.
+    pub fn initialize_ve(ctx: Context<InitializeVe>) -> Result<()> {
+        let clock = Clock::get()?;
+        let ve_state = &mut ctx.accounts.ve_state;
+        ve_state.global_state = ctx.accounts.global_state.key();
+        ve_state.bias = 0;
+        ve_state.slope = 0;
+        ve_state.last_checkpoint = clock.unix_timestamp;
+        ve_state.slope_changes = vec![0; VE_SCHEDULE_WEEKS];
+        ve_state.bump = ctx.bumps.ve_state;
+        emit!(VeInitialized {
+            global_state: ve_state.global_state,
+            ve_state: ve_state.key(),
+        });
+        Ok(())
+    }
+
+    /// Set the longest vote-escrow lock and the boost it earns. Existing locks keep their
+    /// slope; a new boost applies to each position the next time it is touched.
+    pub fn configure_ve(ctx: Context<UpdateParameters>, max_lock: i64, max_boost: u64) -> Result<()> {
+        require!(
+            (WEEK..=MAX_VE_LOCK).contains(&max_lock) && max_boost <= MAX_VE_BOOST,
+            CustomError::InvalidVeConfig
+        );
+        let state = &mut ctx.accounts.global_state;
+        state.ve_max_lock = max_lock;
+        state.ve_max_boost = max_boost;
+        emit!(VeConfigured { max_lock, max_boost });
+        Ok(())
+    }
+
+    /// Set the proposal deposit, voting schedule and quorum thresholds. Applies to
+    /// proposals submitted afterwards.
+    pub fn configure_proposals(ctx: Context<UpdateParameters>, config: ProposalConfig) -> Result<()> {
+        config.validate()?;
+        let state = &mut ctx.accounts.global_state;
+        // Deposits need somewhere to go before they can be charged.
+        require!(
+            config.deposit == 0 || state.proposal_deposit_vault != Pubkey::default(),
+            CustomError::InvalidProposalConfig
+        );
+        state.proposal_config = config.clone();
+        emit!(ProposalsConfigured { config });
+        Ok(())
+    }
+
+    /// Create the program-owned escrow for proposal deposits. Its authority PDA signs
+    /// only refunds and slashes.
+    pub fn initialize_proposal_deposits(ctx: Context<InitializeProposalDeposits>) -> Result<()> {
+        ctx.accounts.global_state.proposal_deposit_vault = ctx.accounts.deposit_vault.key();
+        emit!(ProposalDepositsInitialized {
+            global_state: ctx.accounts.global_state.key(),
+            deposit_vault: ctx.accounts.deposit_vault.key(),
+        });
+        Ok(())
+    }
+
+    /// Set how long new stake waits in the warm-up balance before it starts earning.
+    pub fn set_warmup_period(ctx: Context<UpdateParameters>, warmup_period: i64) -> Result<()> {
+        require!(warmup_period >= 0, CustomError::InvalidWarmupPeriod);
+        let state = &mut ctx.accounts.global_state;
+        state.warmup_period = warmup_period;
+        emit!(WarmupPeriodUpdated { warmup_period });
+        Ok(())
+    }
+
+    /// Replace the loyalty schedule applied to claims. Breakpoints must be in increasing
+    /// `min_duration` order with non-decreasing multipliers.
+    pub fn set_time_multiplier_schedule(
+        ctx: Context<UpdateParameters>,
+        schedule: Vec<MultiplierBreakpoint>,
+        interpolate: bool,
+    ) -> Result<()> {
+        validate_multiplier_schedule(&schedule)?;
+        let state = &mut ctx.accounts.global_state;
+        state.time_multiplier_schedule = schedule.clone();
+        state.interpolate_time_multiplier = interpolate;
+        emit!(TimeMultiplierScheduleUpdated { schedule, interpolate });
+        Ok(())
+    }
+
+    /// Configure how the utilization multiplier is sourced and clamped.
+    pub fn configure_utilization(
+        ctx: Context<UpdateParameters>,
+        mode: UtilizationMode,
+        window: i64,
+        target_fee_yield_bps: u64,
+        min_multiplier: u64,
+        max_multiplier: u64,
+    ) -> Result<()> {
+        require!(window > 0, CustomError::InvalidUtilizationConfig);
+        require!(target_fee_yield_bps > 0, CustomError::InvalidUtilizationConfig);
+        require!(
+            min_multiplier > 0 && min_multiplier <= max_multiplier && max_multiplier <= MAX_DERIVED_UTILIZATION,
+            CustomError::InvalidUtilizationConfig
+        );
+        let clock = Clock::get()?;
+        let state = &mut ctx.accounts.global_state;
+        let old = ProtocolParameters::current(state);
+        state.utilization_mode = mode;
+        state.utilization_window = window;
+        state.target_fee_yield_bps = target_fee_yield_bps;
+        state.min_utilization_multiplier = min_multiplier;
+        state.max_utilization_multiplier = max_multiplier;
+        refresh_utilization(state, clock.unix_timestamp)?;
+        emit!(ParametersUpdated {
+            old,
+            new: ProtocolParameters::current(state),
+        });
+        Ok(())
+    }
+
+    /// Nudge the derived utilization multiplier by a bounded amount (on-chain mode only).
+    pub fn adjust_utilization(ctx: Context<UpdateParameters>, adjustment: i64) -> Result<()> {
+        require!(
+            adjustment.abs() <= MAX_UTILIZATION_ADJUSTMENT,
+            CustomError::UtilizationAdjustmentTooLarge
+        );
+        let clock = Clock::get()?;
+        let state = &mut ctx.accounts.global_state;
+        require!(
+            state.utilization_mode == UtilizationMode::OnChain,
+            CustomError::InvalidUtilizationConfig
+        );
+        let old = ProtocolParameters::current(state);
+        state.utilization_adjustment = adjustment;
+        refresh_utilization(state, clock.unix_timestamp)?;
+        emit!(ParametersUpdated {
+            old,
+            new: ProtocolParameters::current(state),
+        });
+        Ok(())
+    }
+
+    /// Stake $PYT tokens into the given pool.
+    pub fn stake(ctx: Context<Stake>, amount: u64) -> Result<()> {
+        let clock = Clock::get()?;
+        let state = &mut ctx.accounts.global_state;
+        let pool = &mut ctx.accounts.pool;
+        let user = &mut ctx.accounts.user_stake;
+        require!(!pool.retired, CustomError::PoolRetired);
+        // A funded position stays in its pool; switching goes through `extend_lockup`.
+        let has_balance = user.staked_amount > 0 || user.warming_amount > 0;
+        require!(!has_balance || user.pool == pool.key(), CustomError::PoolMismatch);
+        if user.owner == Pubkey::default() {
+            user.owner = ctx.accounts.user.key();
         }
+        user.pool = pool.key();
+        update_reward_accrual(state, clock.unix_timestamp)?;
+        pool.sync(state)?;
+        user.promote_warming(state, pool, clock.unix_timestamp)?;
+        user.settle_rewards(pool.acc_reward_per_share)?;
 
         token::transfer(
             CpiContext::new(
@@ -111,55 +743,73 @@ pub mod perpetual_yield_token {
             amount,
         )?;
 
-        user.staked_amount = user.staked_amount.checked_add(amount).ok_or(CustomError::MathOverflow)?;
-        state.total_staked = state.total_staked.checked_add(amount).ok_or(CustomError::MathOverflow)?;
-        user.reward_debt = (((user.staked_amount as u128)
-            .checked_mul(state.acc_reward_per_share as u128)
-            .ok_or(CustomError::MathOverflow)?)
-            / REWARD_MULTIPLIER as u128)
-            .try_into()
-            .unwrap();
-        user.stake_timestamp = clock.unix_timestamp;
+        let prior_balance = user.staked_amount.checked_add(user.warming_amount).ok_or(CustomError::MathOverflow)?;
+        user.stake_timestamp = weighted_stake_timestamp(prior_balance, user.stake_timestamp, amount, clock.unix_timestamp)?;
+        user.push_lot(StakeLot { amount, timestamp: clock.unix_timestamp, pool: pool.key() })?;
+        if state.warmup_period > 0 {
+            // A top-up pushes the pending balance's warm-up out only in proportion to its
+            // size, so small deposits cannot hold back stake that is already warming.
+            let ready_at = clock.unix_timestamp
+                .checked_add(state.warmup_period)
+                .ok_or(CustomError::MathOverflow)?;
+            user.warmup_end = weighted_stake_timestamp(user.warming_amount, user.warmup_end, amount, ready_at)?;
+            user.warming_amount = user.warming_amount.checked_add(amount).ok_or(CustomError::MathOverflow)?;
+        } else {
+            user.staked_amount = user.staked_amount.checked_add(amount).ok_or(CustomError::MathOverflow)?;
+            state.total_staked = state.total_staked.checked_add(amount).ok_or(CustomError::MathOverflow)?;
+            pool.total_staked = pool.total_staked.checked_add(amount).ok_or(CustomError::MathOverflow)?;
+        }
+        user.update_shares(state, pool, clock.unix_timestamp)?;
         user.last_withdrawal_time = clock.unix_timestamp;
-        user.pool_type = pool_type;
+        ctx.accounts.stake_history.record(state, clock.unix_timestamp)?;
+        emit!(Staked {
+            user_stake: user.key(),
+            owner: user.owner,
+            pool: pool.key(),
+            amount,
+            staked_amount: user.staked_amount,
+            warming_amount: user.warming_amount,
+            total_staked: state.total_staked,
+        });
         Ok(())
     }
 
     /// Batch stake.
-    pub fn batch_stake(ctx: Context<Stake>, amounts: Vec<u64>, pool_type: u8) -> Result<()> {
+    pub fn batch_stake(ctx: Context<Stake>, amounts: Vec<u64>) -> Result<()> {
         let total: u64 = amounts.iter().sum();
-        stake(ctx, total, pool_type)
+        stake(ctx, total)
     }
 
     /// Unstake $PYT tokens.
     pub fn unstake(ctx: Context<Unstake>, amount: u64) -> Result<()> {
         let clock = Clock::get()?;
         let state = &mut ctx.accounts.global_state;
+        let pool = &mut ctx.accounts.pool;
         let user = &mut ctx.accounts.user_stake;
-        require!(user.staked_amount >= amount, CustomError::InsufficientStake);
         require!(
             clock.unix_timestamp - user.last_withdrawal_time >= state.min_withdraw_interval,
             CustomError::WithdrawalTooFrequent
         );
+        update_reward_accrual(state, clock.unix_timestamp)?;
+        pool.sync(state)?;
+        user.promote_warming(state, pool, clock.unix_timestamp)?;
+        let total_balance = user.staked_amount.checked_add(user.warming_amount).ok_or(CustomError::MathOverflow)?;
+        require!(total_balance >= amount, CustomError::InsufficientStake);
+        let remaining_stake = total_balance - amount;
+        require!(
+            clock.unix_timestamp >= user.ve_lock_end || remaining_stake >= user.ve_amount,
+            CustomError::VeLocked
+        );
+        user.settle_rewards(pool.acc_reward_per_share)?;
 
-        let accumulated = (user.staked_amount as u128)
-            .checked_mul(state.acc_reward_per_share as u128)
-            .ok_or(CustomError::MathOverflow)?
-            / REWARD_MULTIPLIER as u128;
-        let pending = accumulated.checked_sub(user.reward_debt as u128)
-            .ok_or(CustomError::MathOverflow)?;
-        user.pending_rewards = user.pending_rewards.checked_add(pending as u64)
-            .ok_or(CustomError::MathOverflow)?;
-
-        // Clone pool info so we don't move it.
-        let pool = state.pool_info[user.pool_type as usize].clone();
-        let staked_duration = clock.unix_timestamp - user.stake_timestamp;
-        let mut amount_after_penalty = amount;
-        if staked_duration < pool.lockup_period {
-            let penalty = amount.checked_mul(state.early_withdrawal_penalty)
-                .ok_or(CustomError::MathOverflow)?
-                / 10_000;
-            amount_after_penalty = amount.checked_sub(penalty).ok_or(CustomError::MathOverflow)?;
+        // Only the lots still inside the pool's lockup are penalized, and the penalty is
+        // routed according to the pool's configuration.
+        let penalty = user.consume_lots(amount, clock.unix_timestamp, pool.key(), &pool.info, pool.max_penalty_bps(state))?;
+        let mut routed = PenaltyRouting::default();
+        routed.add(&pool.info, penalty)?;
+        let amount_after_penalty = amount.checked_sub(penalty).ok_or(CustomError::MathOverflow)?;
+        let to_reward_vault = routed.insurance.checked_add(routed.redistribute).ok_or(CustomError::MathOverflow)?;
+        if to_reward_vault > 0 {
             token::transfer(
                 CpiContext::new(
                     ctx.accounts.token_program.to_account_info(),
@@ -170,20 +820,55 @@ pub mod perpetual_yield_token {
                     },
                 )
                 .with_signer(&[&[b"vault", &[ctx.bumps.vault_authority]]]),
-                penalty,
+                to_reward_vault,
+            )?;
+            state.insurance_fund = state.insurance_fund.checked_add(routed.insurance).ok_or(CustomError::MathOverflow)?;
+        }
+        if routed.treasury > 0 {
+            let treasury = ctx.accounts.treasury.as_ref().ok_or(CustomError::MissingPenaltyAccount)?;
+            token::transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.staking_vault.to_account_info(),
+                        to: treasury.to_account_info(),
+                        authority: ctx.accounts.vault_authority.to_account_info(),
+                    },
+                )
+                .with_signer(&[&[b"vault", &[ctx.bumps.vault_authority]]]),
+                routed.treasury,
+            )?;
+        }
+        if routed.burn > 0 {
+            let token_mint = ctx.accounts.token_mint.as_ref().ok_or(CustomError::MissingPenaltyAccount)?;
+            token::burn(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    Burn {
+                        mint: token_mint.to_account_info(),
+                        from: ctx.accounts.staking_vault.to_account_info(),
+                        authority: ctx.accounts.vault_authority.to_account_info(),
+                    },
+                )
+                .with_signer(&[&[b"vault", &[ctx.bumps.vault_authority]]]),
+                routed.burn,
             )?;
-            state.insurance_fund = state.insurance_fund.checked_add(penalty).ok_or(CustomError::MathOverflow)?;
         }
 
-        user.staked_amount = user.staked_amount.checked_sub(amount).ok_or(CustomError::MathOverflow)?;
-        state.total_staked = state.total_staked.checked_sub(amount).ok_or(CustomError::MathOverflow)?;
-        user.reward_debt = (((user.staked_amount as u128)
-            .checked_mul(state.acc_reward_per_share as u128)
-            .ok_or(CustomError::MathOverflow)?)
-            / REWARD_MULTIPLIER as u128)
-            .try_into()
-            .unwrap();
+        // The earning stake is the oldest balance, so it is withdrawn first, matching the
+        // FIFO order `consume_lots` priced the penalty in.
+        let from_stake = amount.min(user.staked_amount);
+        let from_warming = amount - from_stake;
+        user.warming_amount = user.warming_amount.checked_sub(from_warming).ok_or(CustomError::MathOverflow)?;
+        user.staked_amount = user.staked_amount.checked_sub(from_stake).ok_or(CustomError::MathOverflow)?;
+        state.total_staked = state.total_staked.checked_sub(from_stake).ok_or(CustomError::MathOverflow)?;
+        pool.total_staked = pool.total_staked.checked_sub(from_stake).ok_or(CustomError::MathOverflow)?;
+        user.update_shares(state, pool, clock.unix_timestamp)?;
         user.last_withdrawal_time = clock.unix_timestamp;
+        ctx.accounts.stake_history.record(state, clock.unix_timestamp)?;
+        // Redistributed penalties are queued after the unstaker's debt is reset, so the
+        // remaining balance (theirs included) shares in them like any other deposit.
+        queue_rewards(state, routed.redistribute, clock.unix_timestamp)?;
 
         token::transfer(
             CpiContext::new(
@@ -197,96 +882,615 @@ pub mod perpetual_yield_token {
             .with_signer(&[&[b"vault", &[ctx.bumps.vault_authority]]]),
             amount_after_penalty,
         )?;
+        emit!(Unstaked {
+            user_stake: user.key(),
+            owner: user.owner,
+            pool: pool.key(),
+            amount,
+            penalty,
+            received: amount_after_penalty,
+            staked_amount: user.staked_amount,
+            total_staked: state.total_staked,
+        });
         Ok(())
     }
 
-    /// Batch unstake.
-    pub fn batch_unstake(ctx: Context<Unstake>, amounts: Vec<u64>) -> Result<()> {
-        let total: u64 = amounts.iter().sum();
-        unstake(ctx, total)
+    /// Quote what `claim_rewards` would pay right now. Runs the claim against copies of the
+    /// accounts, so nothing is written; call it through `simulateTransaction`. Claim gating
+    /// is reported in `claimable_at` rather than enforced.
+    pub fn quote_rewards(ctx: Context<QuoteRewards>) -> Result<RewardQuote> {
+        let clock = Clock::get()?;
+        let mut state = (*ctx.accounts.global_state).clone();
+        let mut pool = (*ctx.accounts.pool).clone();
+        let mut user = (*ctx.accounts.user_stake).clone();
+        update_reward_accrual(&mut state, clock.unix_timestamp)?;
+        pool.sync(&mut state)?;
+        user.promote_warming(&mut state, &mut pool, clock.unix_timestamp)?;
+        refresh_utilization(&mut state, clock.unix_timestamp)?;
+        user.settle_rewards(pool.acc_reward_per_share)?;
+        user.update_shares(&mut state, &mut pool, clock.unix_timestamp)?;
+        let mut quote = price_claim(
+            &mut state,
+            &ctx.accounts.rebate_config,
+            user.pending_rewards,
+            user.stake_timestamp,
+            user.trade_volume_7d,
+            100,
+            clock.unix_timestamp,
+        )?;
+        quote.payout = pay_claim(&mut state, &mut user.owed_rewards, quote.total_reward, ctx.accounts.reward_vault.amount)?;
+        quote.owed_rewards = user.owed_rewards;
+        Ok(quote)
     }
 
-    /// Deposit transaction revenue into the reward vault.
-    pub fn deposit_transaction_fee(ctx: Context<DepositFee>, amount: u64) -> Result<()> {
+    /// Quote what `lp_claim_rewards` would pay right now; see `quote_rewards`.
+    pub fn quote_lp_rewards(ctx: Context<QuoteLpRewards>) -> Result<RewardQuote> {
         let clock = Clock::get()?;
-        let state = &mut ctx.accounts.global_state;
-        token::transfer(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                Transfer {
-                    from: ctx.accounts.depositor_token_account.to_account_info(),
-                    to: ctx.accounts.reward_vault.to_account_info(),
-                    authority: ctx.accounts.depositor.to_account_info(),
-                },
-            ),
-            amount,
+        let mut state = (*ctx.accounts.global_state).clone();
+        let mut lp_user = (*ctx.accounts.lp_user_stake).clone();
+        update_reward_accrual(&mut state, clock.unix_timestamp)?;
+        let promoted = lp_user.promote_warming(state.lp_acc_reward_per_share, clock.unix_timestamp)?;
+        state.lp_total_staked = state.lp_total_staked.checked_add(promoted).ok_or(CustomError::MathOverflow)?;
+        refresh_utilization(&mut state, clock.unix_timestamp)?;
+        let pending_from_stake = accrued_rewards(lp_user.staked_amount, state.lp_acc_reward_per_share)?
+            .checked_sub(lp_user.reward_debt)
+            .ok_or(CustomError::MathOverflow)?;
+        let base_reward = lp_user.pending_rewards.checked_add(pending_from_stake)
+            .ok_or(CustomError::MathOverflow)?;
+        let mut quote = price_claim(
+            &mut state,
+            &ctx.accounts.rebate_config,
+            base_reward,
+            lp_user.stake_timestamp,
+            lp_user.trade_volume_7d,
+            LP_BONUS_MULTIPLIER,
+            clock.unix_timestamp,
         )?;
-        let insurance_fee = amount.checked_mul(state.insurance_fee_percent)
-            .ok_or(CustomError::MathOverflow)? / 10_000;
-        let distributable = amount.checked_sub(insurance_fee).ok_or(CustomError::MathOverflow)?;
-        state.insurance_fund = state.insurance_fund.checked_add(insurance_fee).ok_or(CustomError::MathOverflow)?;
-        if state.total_staked > 0 {
-            let add_amount: u64 = (((distributable as u128)
-                .checked_mul(REWARD_MULTIPLIER as u128)
-                .ok_or(CustomError::MathOverflow)?)
-                / (state.total_staked as u128))
-                .try_into()
-                .unwrap();
-            state.acc_reward_per_share = state.acc_reward_per_share.checked_add(add_amount).ok_or(CustomError::MathOverflow)?;
+        quote.payout = pay_claim(&mut state, &mut lp_user.owed_rewards, quote.total_reward, ctx.accounts.reward_vault.amount)?;
+        quote.owed_rewards = lp_user.owed_rewards;
+        Ok(quote)
+    }
+
+    /// Quote the early-withdrawal penalty `unstake(amount)` would charge right now.
+    pub fn quote_unstake_penalty(ctx: Context<QuotePenalty>, amount: u64) -> Result<u64> {
+        let clock = Clock::get()?;
+        let state = &ctx.accounts.global_state;
+        ctx.accounts.user_stake.quote_penalty(
+            amount,
+            clock.unix_timestamp,
+            ctx.accounts.pool.key(),
+            &ctx.accounts.pool.info,
+            ctx.accounts.pool.max_penalty_bps(state),
+        )
+    }
+
+    /// Earning balance of a position at `timestamp`, from its checkpoint history.
+    pub fn staked_balance_at(ctx: Context<QueryPosition>, timestamp: i64) -> Result<u64> {
+        let checkpoint = ctx.accounts.user_stake
+            .checkpoint_at(timestamp)
+            .ok_or(CustomError::SnapshotUnavailable)?;
+        Ok(checkpoint.staked_amount)
+    }
+
+    /// `total_staked` at `timestamp`, from the `StakeHistory` page covering it. A page
+    /// before the current one must come with the page after it, to show the totals did
+    /// not change again before `timestamp`.
+    pub fn total_staked_at(ctx: Context<QueryStakeHistory>, timestamp: i64) -> Result<u64> {
+        let history = &ctx.accounts.stake_history;
+        match &ctx.accounts.next_page {
+            Some(next) => require!(
+                next.page == history.page + 1
+                    && next.checkpoints.first().is_none_or(|first| first.timestamp > timestamp),
+                CustomError::SnapshotUnavailable
+            ),
+            None => require!(
+                history.page == ctx.accounts.global_state.stake_history_page,
+                CustomError::SnapshotUnavailable
+            ),
         }
-        state.last_fee_deposit_time = clock.unix_timestamp;
+        let checkpoint = history.checkpoint_at(timestamp).ok_or(CustomError::SnapshotUnavailable)?;
+        Ok(checkpoint.total_staked)
+    }
+
+    /// Open the current `StakeHistory` page. Anyone may pay for it; instructions that
+    /// move stake fail until it exists.
+    pub fn open_stake_history_page(ctx: Context<OpenStakeHistoryPage>) -> Result<()> {
+        let history = &mut ctx.accounts.stake_history;
+        history.global_state = ctx.accounts.global_state.key();
+        history.page = ctx.accounts.global_state.stake_history_page;
+        history.checkpoints = Vec::new();
+        history.bump = ctx.bumps.stake_history;
+        emit!(StakeHistoryPageOpened {
+            stake_history: history.key(),
+            page: history.page,
+        });
         Ok(())
     }
 
-    /// Claim pending rewards.
-    pub fn claim_rewards(ctx: Context<ClaimRewards>, proof: String) -> Result<()> {
-        let mut ctx = ctx;
-        _claim_rewards(&mut ctx, proof)
+    /// Create an empty position in `pool` owned by the signer.
+    pub fn open_position(ctx: Context<OpenPosition>) -> Result<()> {
+        require!(!ctx.accounts.pool.retired, CustomError::PoolRetired);
+        let user = &mut ctx.accounts.user_stake;
+        user.owner = ctx.accounts.owner.key();
+        user.pool = ctx.accounts.pool.key();
+        user.delegate = Pubkey::default();
+        user.lots = Vec::new();
+        user.checkpoints = Vec::new();
+        emit!(PositionOpened {
+            user_stake: user.key(),
+            owner: user.owner,
+            pool: user.pool,
+        });
+        Ok(())
     }
 
-    /// Auto-compound: claim rewards and restake them.
-    pub fn auto_compound(ctx: Context<AutoCompound>, proof: String, compounded_amount: u64) -> Result<()> {
-        // Build a new ClaimRewards struct from AutoCompound accounts.
-        let mut claim_accounts = ClaimRewards {
-            global_state: ctx.accounts.global_state.clone(),
-            user_stake: ctx.accounts.user_stake.clone(),
-            reward_vault: ctx.accounts.reward_vault.clone(),
-            user_reward_token_account: ctx.accounts.user_token_account.clone(),
-            vault_authority: ctx.accounts.vault_authority.clone(),
-            token_program: ctx.accounts.token_program.clone(),
-        };
-        // Create a mutable Context for ClaimRewards using default bumps.
-        let mut claim_ctx = Context {
-            program_id: ctx.program_id,
-            accounts: &mut claim_accounts,
-            remaining_accounts: ctx.remaining_accounts.clone(),
-            bumps: Default::default(),
-        };
-        _claim_rewards(&mut claim_ctx, proof)?;
+    /// Move a position into a pool with an equal or longer lockup. Every lot is re-anchored
+    /// to the new pool starting now, and the position's reward shares are re-weighted.
+    pub fn extend_lockup(ctx: Context<ExtendLockup>) -> Result<()> {
         let clock = Clock::get()?;
         let state = &mut ctx.accounts.global_state;
+        let from_pool = &mut ctx.accounts.from_pool;
+        let to_pool = &mut ctx.accounts.to_pool;
         let user = &mut ctx.accounts.user_stake;
-        token::transfer(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                Transfer {
-                    from: ctx.accounts.staking_vault.to_account_info(),
-                    to: ctx.accounts.user_token_account.to_account_info(),
-                    authority: ctx.accounts.vault_authority.to_account_info(),
-                },
-            )
-            .with_signer(&[&[b"vault", &[ctx.bumps.vault_authority]]]),
-            compounded_amount,
+        require!(from_pool.key() != to_pool.key(), CustomError::LockupNotExtended);
+        require!(!to_pool.retired, CustomError::PoolRetired);
+        require!(
+            to_pool.info.lockup_period >= from_pool.info.lockup_period,
+            CustomError::LockupNotExtended
+        );
+        update_reward_accrual(state, clock.unix_timestamp)?;
+        from_pool.sync(state)?;
+        to_pool.sync(state)?;
+        user.promote_warming(state, from_pool, clock.unix_timestamp)?;
+        user.settle_rewards(from_pool.acc_reward_per_share)?;
+        user.leave_pool(state, from_pool, clock.unix_timestamp)?;
+
+        // After re-anchoring every lot shares a timestamp, so they collapse into one.
+        let amount = user.staked_amount.checked_add(user.warming_amount).ok_or(CustomError::MathOverflow)?;
+        user.lots.clear();
+        if amount > 0 {
+            user.lots.push(StakeLot { amount, timestamp: clock.unix_timestamp, pool: to_pool.key() });
+        }
+        user.pool = to_pool.key();
+        user.join_pool(state, to_pool, clock.unix_timestamp)?;
+        ctx.accounts.stake_history.record(state, clock.unix_timestamp)?;
+
+        let lockup_end = clock.unix_timestamp
+            .checked_add(to_pool.info.lockup_period)
+            .ok_or(CustomError::MathOverflow)?;
+        emit!(LockupExtended {
+            user_stake: user.key(),
+            owner: user.owner,
+            from_pool: from_pool.key(),
+            to_pool: to_pool.key(),
+            amount,
+            reward_shares: user.reward_shares,
+            lockup_end,
+        });
+        Ok(())
+    }
+
+    /// Move a position out of a retired pool into an active one. Principal and settled
+    /// rewards move with the position; no tokens leave `staking_vault`. Migrated lots keep
+    /// the retired pool as their `pool`, so they stay penalty-free as they were before the
+    /// move; only new deposits are locked under the target pool.
+    pub fn migrate_position(ctx: Context<MigratePosition>) -> Result<()> {
+        let clock = Clock::get()?;
+        let state = &mut ctx.accounts.global_state;
+        let from_pool = &mut ctx.accounts.from_pool;
+        let to_pool = &mut ctx.accounts.to_pool;
+        let user = &mut ctx.accounts.user_stake;
+        require!(from_pool.retired, CustomError::PoolNotRetired);
+        require!(!to_pool.retired, CustomError::PoolRetired);
+        update_reward_accrual(state, clock.unix_timestamp)?;
+        from_pool.sync(state)?;
+        to_pool.sync(state)?;
+        user.promote_warming(state, from_pool, clock.unix_timestamp)?;
+        user.settle_rewards(from_pool.acc_reward_per_share)?;
+        user.leave_pool(state, from_pool, clock.unix_timestamp)?;
+        user.pool = to_pool.key();
+        user.join_pool(state, to_pool, clock.unix_timestamp)?;
+        ctx.accounts.stake_history.record(state, clock.unix_timestamp)?;
+
+        emit!(PositionMigrated {
+            user_stake: user.key(),
+            owner: user.owner,
+            from_pool: from_pool.key(),
+            to_pool: to_pool.key(),
+            amount: user.staked_amount.checked_add(user.warming_amount).ok_or(CustomError::MathOverflow)?,
+            pending_rewards: user.pending_rewards,
+        });
+        Ok(())
+    }
+
+    /// Lock `amount` more of the position's stake in vote escrow and/or push the unlock
+    /// time out to `unlock_time` (rounded down to a week; 0 keeps the current end). Locked
+    /// stake cannot be unstaked until the lock ends.
+    pub fn lock_ve(ctx: Context<LockVe>, amount: u64, unlock_time: i64) -> Result<()> {
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        let state = &mut ctx.accounts.global_state;
+        let pool = &mut ctx.accounts.pool;
+        let ve_state = &mut ctx.accounts.ve_state;
+        let user = &mut ctx.accounts.user_stake;
+        update_reward_accrual(state, now)?;
+        pool.sync(state)?;
+        user.promote_warming(state, pool, now)?;
+        user.settle_rewards(pool.acc_reward_per_share)?;
+        ve_state.checkpoint(now)?;
+
+        let active = user.ve_lock_end > now;
+        let (old_amount, old_slope, old_end) = if active {
+            (user.ve_amount, user.ve_slope, user.ve_lock_end)
+        } else {
+            (0, 0, 0)
+        };
+        let new_end = if unlock_time == 0 && active { old_end } else { unlock_time / WEEK * WEEK };
+        let max_end = now.checked_add(state.ve_max_lock).ok_or(CustomError::MathOverflow)?;
+        require!(new_end > now && new_end >= old_end && new_end <= max_end, CustomError::InvalidVeLock);
+        let new_amount = old_amount.checked_add(amount).ok_or(CustomError::MathOverflow)?;
+        require!(new_amount > 0, CustomError::InvalidVeLock);
+        require!(new_amount <= user.staked_amount, CustomError::InsufficientStake);
+        let new_slope = new_amount as u128 * VE_PRECISION / state.ve_max_lock as u128;
+        ve_state.replace_lock(old_slope, old_end, new_slope, new_end, now)?;
+
+        user.ve_amount = new_amount;
+        user.ve_slope = new_slope;
+        user.ve_lock_end = new_end;
+        user.update_shares(state, pool, now)?;
+        ctx.accounts.stake_history.record(state, now)?;
+        emit!(VeLockUpdated {
+            user_stake: user.key(),
+            owner: user.owner,
+            ve_amount: new_amount,
+            ve_lock_end: new_end,
+            reward_shares: user.reward_shares,
+        });
+        Ok(())
+    }
+
+    /// Let `delegate` cast this position's proposal votes. Takes effect for proposals
+    /// snapshotted from now on.
+    pub fn delegate_votes(ctx: Context<DelegateVotes>, delegate: Pubkey) -> Result<()> {
+        require!(delegate != Pubkey::default(), CustomError::InvalidDelegate);
+        set_delegate(&mut ctx.accounts.user_stake, delegate)
+    }
+
+    /// Take proposal voting back from the current delegate.
+    pub fn undelegate(ctx: Context<DelegateVotes>) -> Result<()> {
+        set_delegate(&mut ctx.accounts.user_stake, Pubkey::default())
+    }
+
+    /// Create the gauge for a pool, or for LP stake when `target` is the global state.
+    pub fn create_gauge(ctx: Context<CreateGauge>, target: Pubkey) -> Result<()> {
+        let global_key = ctx.accounts.global_state.key();
+        let is_pool = ctx.accounts.pool.as_ref().is_some_and(|pool| pool.key() == target);
+        require!(is_pool || target == global_key, CustomError::InvalidGaugeTarget);
+        let state = &mut ctx.accounts.global_state;
+        require!(state.gauge_count < MAX_GAUGES, CustomError::TooManyGauges);
+        state.gauge_count += 1;
+        let gauge = &mut ctx.accounts.gauge;
+        gauge.global_state = global_key;
+        gauge.target = target;
+        gauge.votes_epoch = 0;
+        gauge.votes = 0;
+        gauge.prior_votes_epoch = 0;
+        gauge.prior_votes = 0;
+        gauge.weight_epoch = 0;
+        gauge.weight = 0;
+        gauge.bump = ctx.bumps.gauge;
+        emit!(GaugeCreated {
+            gauge: gauge.key(),
+            target,
+        });
+        Ok(())
+    }
+
+    /// Put `weight_bps` of the position's vote-escrow power behind a gauge for the next
+    /// epoch. A position can spread at most 10,000 bps across gauges per epoch.
+    pub fn vote_gauge(ctx: Context<VoteGauge>, weight_bps: u64) -> Result<()> {
+        let clock = Clock::get()?;
+        let epoch = (clock.unix_timestamp / WEEK) as u64;
+        let gauge = &mut ctx.accounts.gauge;
+        let user = &mut ctx.accounts.user_stake;
+        let target_epoch = epoch + 1;
+        if gauge.votes_epoch != target_epoch {
+            // Last epoch's tally applies to this epoch; keep it until it is checkpointed.
+            gauge.prior_votes_epoch = gauge.votes_epoch;
+            gauge.prior_votes = gauge.votes;
+            gauge.votes_epoch = target_epoch;
+            gauge.votes = 0;
+        }
+        if user.gauge_vote_epoch != epoch {
+            user.gauge_vote_epoch = epoch;
+            user.gauge_vote_bps = 0;
+        }
+        let used = user.gauge_vote_bps.checked_add(weight_bps).ok_or(CustomError::MathOverflow)?;
+        require!(used <= 10_000, CustomError::GaugeVoteExceeded);
+        // Weight is measured at the start of the epoch it will apply to.
+        let next_epoch_start = (epoch as i64 + 1) * WEEK;
+        let power = (user.voting_power_at(next_epoch_start) as u128 * weight_bps as u128 / 10_000) as u64;
+        require!(power > 0, CustomError::NoVotingPower);
+        user.gauge_vote_bps = used;
+        gauge.votes = gauge.votes.checked_add(power).ok_or(CustomError::MathOverflow)?;
+        emit!(GaugeVoted {
+            gauge: gauge.key(),
+            user_stake: user.key(),
+            epoch: target_epoch,
+            weight_bps,
+            power,
+        });
+        Ok(())
+    }
+
+    /// Apply the votes cast last epoch as weights for the current epoch; a gauge nobody
+    /// voted for last epoch drops to zero weight. Votes cast this epoch are tallied for the
+    /// next one, so they never affect the result. Pass every gauge once in
+    /// `remaining_accounts`, each followed by its pool unless it is the LP gauge, so
+    /// `total_gauge_weight` never mixes two epochs. Later calls in the same epoch do nothing.
+    pub fn checkpoint_gauges<'info>(ctx: Context<'_, '_, 'info, 'info, CheckpointGauges<'info>>) -> Result<()> {
+        let clock = Clock::get()?;
+        let epoch = (clock.unix_timestamp / WEEK) as u64;
+        let state = &mut ctx.accounts.global_state;
+        let global_key = state.key();
+        // Emissions up to now are split with the weights they were earned under.
+        update_reward_accrual(state, clock.unix_timestamp)?;
+        if state.gauge_epoch == epoch {
+            return Ok(());
+        }
+        let mut applied = 0u64;
+        let mut accounts = ctx.remaining_accounts.iter();
+        while let Some(gauge_info) = accounts.next() {
+            let mut gauge = Account::<Gauge>::try_from(gauge_info)?;
+            require_keys_eq!(gauge.global_state, global_key, CustomError::InvalidGaugeTarget);
+            let is_lp = gauge.target == global_key;
+            let mut pool = if is_lp {
+                None
+            } else {
+                let pool_info = accounts.next().ok_or(CustomError::InvalidGaugeTarget)?;
+                require_keys_eq!(pool_info.key(), gauge.target, CustomError::InvalidGaugeTarget);
+                let pool = Account::<Pool>::try_from(pool_info)?;
+                require_keys_eq!(pool.global_state, global_key, CustomError::InvalidGaugeTarget);
+                Some(pool)
+            };
+            // A gauge already applied this epoch was passed twice.
+            require!(gauge.weight_epoch != epoch, CustomError::IncompleteGaugeCheckpoint);
+            let new_weight = gauge.votes_for(epoch);
+            match pool.as_mut() {
+                Some(pool) => {
+                    pool.sync(state)?;
+                    pool.gauge_weight = new_weight;
+                }
+                None => state.lp_gauge_weight = new_weight,
+            }
+            state.total_gauge_weight = state.total_gauge_weight
+                .checked_sub(gauge.weight)
+                .and_then(|total| total.checked_add(new_weight))
+                .ok_or(CustomError::MathOverflow)?;
+            gauge.weight = new_weight;
+            gauge.weight_epoch = epoch;
+            emit!(GaugeWeightApplied {
+                gauge: gauge.key(),
+                epoch,
+                weight: new_weight,
+            });
+            gauge.exit(ctx.program_id)?;
+            if let Some(pool) = pool {
+                pool.exit(ctx.program_id)?;
+            }
+            applied += 1;
+        }
+        require!(applied == state.gauge_count, CustomError::IncompleteGaugeCheckpoint);
+        state.gauge_epoch = epoch;
+        Ok(())
+    }
+
+    /// Bring a position's shares up to date: promote a matured warm-up balance and
+    /// re-derive the vote-escrow boost, which only ever decays without the owner. Keepers
+    /// call it so a warm-up starts earning at `warmup_end` (see
+    /// `UserStake::promote_warming`) and an untouched lock stops collecting its old boost.
+    pub fn poke_position(ctx: Context<PokePosition>) -> Result<()> {
+        let clock = Clock::get()?;
+        let state = &mut ctx.accounts.global_state;
+        let pool = &mut ctx.accounts.pool;
+        let user = &mut ctx.accounts.user_stake;
+        update_reward_accrual(state, clock.unix_timestamp)?;
+        pool.sync(state)?;
+        let warming = user.warming_amount;
+        user.promote_warming(state, pool, clock.unix_timestamp)?;
+        user.settle_rewards(pool.acc_reward_per_share)?;
+        user.update_shares(state, pool, clock.unix_timestamp)?;
+        ctx.accounts.stake_history.record(state, clock.unix_timestamp)?;
+        if user.warming_amount < warming {
+            emit!(WarmupPromoted {
+                position: user.key(),
+                amount: warming - user.warming_amount,
+                staked_amount: user.staked_amount,
+            });
+        }
+        Ok(())
+    }
+
+    /// LP counterpart of `poke_position`.
+    pub fn poke_lp_position(ctx: Context<PokeLpPosition>) -> Result<()> {
+        let clock = Clock::get()?;
+        let state = &mut ctx.accounts.global_state;
+        update_reward_accrual(state, clock.unix_timestamp)?;
+        let promoted = ctx.accounts.lp_user_stake.promote_warming(state.lp_acc_reward_per_share, clock.unix_timestamp)?;
+        state.lp_total_staked = state.lp_total_staked.checked_add(promoted).ok_or(CustomError::MathOverflow)?;
+        ctx.accounts.stake_history.record(state, clock.unix_timestamp)?;
+        if promoted > 0 {
+            emit!(WarmupPromoted {
+                position: ctx.accounts.lp_user_stake.key(),
+                amount: promoted,
+                staked_amount: ctx.accounts.lp_user_stake.staked_amount,
+            });
+        }
+        Ok(())
+    }
+
+    /// Advance the global vote-escrow curve to now, applying expired locks' slope changes.
+    pub fn checkpoint_ve(ctx: Context<CheckpointVe>) -> Result<()> {
+        let clock = Clock::get()?;
+        ctx.accounts.ve_state.checkpoint(clock.unix_timestamp)
+    }
+
+    /// Batch unstake.
+    pub fn batch_unstake(ctx: Context<Unstake>, amounts: Vec<u64>) -> Result<()> {
+        let total: u64 = amounts.iter().sum();
+        unstake(ctx, total)
+    }
+
+    /// Deposit transaction revenue into the reward vault.
+    pub fn deposit_transaction_fee(ctx: Context<DepositFee>, amount: u64) -> Result<()> {
+        let clock = Clock::get()?;
+        let state = &mut ctx.accounts.global_state;
+        let acc_before = state.acc_reward_per_share;
+        let treasury_cut = amount.checked_mul(state.treasury_fee_bps)
+            .ok_or(CustomError::MathOverflow)? / 10_000;
+        if treasury_cut > 0 {
+            let treasury = ctx.accounts.treasury.as_ref().ok_or(CustomError::MissingTreasuryAccount)?;
+            token::transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.depositor_token_account.to_account_info(),
+                        to: treasury.to_account_info(),
+                        authority: ctx.accounts.depositor.to_account_info(),
+                    },
+                ),
+                treasury_cut,
+            )?;
+        }
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.depositor_token_account.to_account_info(),
+                    to: ctx.accounts.reward_vault.to_account_info(),
+                    authority: ctx.accounts.depositor.to_account_info(),
+                },
+            ),
+            amount - treasury_cut,
+        )?;
+        let insurance_fee = amount.checked_mul(state.insurance_fee_percent)
+            .ok_or(CustomError::MathOverflow)? / 10_000;
+        let mut distributable = amount
+            .checked_sub(insurance_fee)
+            .and_then(|rest| rest.checked_sub(treasury_cut))
+            .ok_or(CustomError::MathOverflow)?;
+        state.insurance_fund = state.insurance_fund.checked_add(insurance_fee).ok_or(CustomError::MathOverflow)?;
+        // Outstanding IOUs are paid down before anything new is allocated.
+        let unreserved_owed = state.total_owed.saturating_sub(state.owed_reserve);
+        let repayment = distributable.min(unreserved_owed);
+        state.owed_reserve = state.owed_reserve.checked_add(repayment).ok_or(CustomError::MathOverflow)?;
+        distributable -= repayment;
+        queue_rewards(state, distributable, clock.unix_timestamp)?;
+        roll_utilization_window(state, clock.unix_timestamp);
+        state.window_fee_total = state.window_fee_total.checked_add(distributable).ok_or(CustomError::MathOverflow)?;
+        refresh_utilization(state, clock.unix_timestamp)?;
+        state.last_fee_deposit_time = clock.unix_timestamp;
+        emit!(FeeDeposited {
+            depositor: ctx.accounts.depositor.key(),
+            amount,
+            treasury_cut,
+            insurance_fee,
+            owed_repayment: repayment,
+            distributable,
+            acc_reward_per_share_delta: state.acc_reward_per_share - acc_before,
+            reward_rate: state.reward_rate,
+        });
+        Ok(())
+    }
+
+    /// Fund the bonus budget that pays for claim multipliers above the base allocation.
+    pub fn fund_bonus_budget(ctx: Context<FundBonusBudget>, amount: u64) -> Result<()> {
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.funder_token_account.to_account_info(),
+                    to: ctx.accounts.reward_vault.to_account_info(),
+                    authority: ctx.accounts.funder.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+        let state = &mut ctx.accounts.global_state;
+        state.bonus_budget = state.bonus_budget.checked_add(amount).ok_or(CustomError::MathOverflow)?;
+        emit!(BonusBudgetFunded {
+            funder: ctx.accounts.funder.key(),
+            amount,
+            bonus_budget: state.bonus_budget,
+        });
+        Ok(())
+    }
+
+    /// Claim pending rewards.
+    pub fn claim_rewards(ctx: Context<ClaimRewards>, proof: String) -> Result<()> {
+        let mut ctx = ctx;
+        _claim_rewards(&mut ctx, proof)
+    }
+
+    /// Auto-compound: claim rewards and restake them.
+    pub fn auto_compound(ctx: Context<AutoCompound>, proof: String, compounded_amount: u64) -> Result<()> {
+        // Build a new ClaimRewards struct from AutoCompound accounts.
+        let mut claim_accounts = ClaimRewards {
+            global_state: ctx.accounts.global_state.clone(),
+            pool: ctx.accounts.pool.clone(),
+            rebate_config: ctx.accounts.rebate_config.clone(),
+            stake_history: ctx.accounts.stake_history.clone(),
+            user_stake: ctx.accounts.user_stake.clone(),
+            reward_vault: ctx.accounts.reward_vault.clone(),
+            user_reward_token_account: ctx.accounts.user_token_account.clone(),
+            vault_authority: ctx.accounts.vault_authority.clone(),
+            token_program: ctx.accounts.token_program.clone(),
+        };
+        // Create a mutable Context for ClaimRewards sharing the vault authority bump.
+        let mut claim_ctx = Context {
+            program_id: ctx.program_id,
+            accounts: &mut claim_accounts,
+            remaining_accounts: ctx.remaining_accounts,
+            bumps: ClaimRewardsBumps { vault_authority: ctx.bumps.vault_authority },
+        };
+        _claim_rewards(&mut claim_ctx, proof)?;
+        // The claim ran against copies; carry its accrual and payout bookkeeping back.
+        ctx.accounts.global_state.set_inner(claim_accounts.global_state.into_inner());
+        ctx.accounts.pool.set_inner(claim_accounts.pool.into_inner());
+        ctx.accounts.user_stake.set_inner(claim_accounts.user_stake.into_inner());
+        ctx.accounts.stake_history.set_inner(claim_accounts.stake_history.into_inner());
+        let clock = Clock::get()?;
+        let state = &mut ctx.accounts.global_state;
+        let pool = &mut ctx.accounts.pool;
+        let user = &mut ctx.accounts.user_stake;
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.staking_vault.to_account_info(),
+                    to: ctx.accounts.user_token_account.to_account_info(),
+                    authority: ctx.accounts.vault_authority.to_account_info(),
+                },
+            )
+            .with_signer(&[&[b"vault", &[ctx.bumps.vault_authority]]]),
+            compounded_amount,
         )?;
+        let prior_balance = user.staked_amount.checked_add(user.warming_amount).ok_or(CustomError::MathOverflow)?;
+        user.stake_timestamp = weighted_stake_timestamp(prior_balance, user.stake_timestamp, compounded_amount, clock.unix_timestamp)?;
+        user.push_lot(StakeLot { amount: compounded_amount, timestamp: clock.unix_timestamp, pool: pool.key() })?;
         user.staked_amount = user.staked_amount.checked_add(compounded_amount).ok_or(CustomError::MathOverflow)?;
         state.total_staked = state.total_staked.checked_add(compounded_amount).ok_or(CustomError::MathOverflow)?;
-        user.stake_timestamp = clock.unix_timestamp;
-        user.reward_debt = (((user.staked_amount as u128)
-            .checked_mul(state.acc_reward_per_share as u128)
-            .ok_or(CustomError::MathOverflow)?)
-            / REWARD_MULTIPLIER as u128)
-            .try_into()
-            .unwrap();
+        pool.total_staked = pool.total_staked.checked_add(compounded_amount).ok_or(CustomError::MathOverflow)?;
+        user.update_shares(state, pool, clock.unix_timestamp)?;
+        ctx.accounts.stake_history.record(state, clock.unix_timestamp)?;
+        emit!(Compounded {
+            user_stake: user.key(),
+            owner: user.owner,
+            pool: pool.key(),
+            amount: compounded_amount,
+            staked_amount: user.staked_amount,
+            total_staked: state.total_staked,
+        });
         Ok(())
     }
 
@@ -295,9 +1499,12 @@ pub mod perpetual_yield_token {
         let clock = Clock::get()?;
         let state = &mut ctx.accounts.global_state;
         let lp_user = &mut ctx.accounts.lp_user_stake;
+        update_reward_accrual(state, clock.unix_timestamp)?;
+        let promoted = lp_user.promote_warming(state.lp_acc_reward_per_share, clock.unix_timestamp)?;
+        state.lp_total_staked = state.lp_total_staked.checked_add(promoted).ok_or(CustomError::MathOverflow)?;
         if lp_user.staked_amount > 0 {
             let accumulated = (lp_user.staked_amount as u128)
-                .checked_mul(state.acc_reward_per_share as u128)
+                .checked_mul(state.lp_acc_reward_per_share)
                 .ok_or(CustomError::MathOverflow)?
                 / REWARD_MULTIPLIER as u128;
             let pending = accumulated.checked_sub(lp_user.reward_debt as u128)
@@ -316,15 +1523,34 @@ pub mod perpetual_yield_token {
             ),
             amount,
         )?;
-        lp_user.staked_amount = lp_user.staked_amount.checked_add(amount).ok_or(CustomError::MathOverflow)?;
+        let prior_balance = lp_user.staked_amount.checked_add(lp_user.warming_amount).ok_or(CustomError::MathOverflow)?;
+        lp_user.stake_timestamp = weighted_stake_timestamp(prior_balance, lp_user.stake_timestamp, amount, clock.unix_timestamp)?;
+        if state.warmup_period > 0 {
+            let ready_at = clock.unix_timestamp
+                .checked_add(state.warmup_period)
+                .ok_or(CustomError::MathOverflow)?;
+            lp_user.warmup_end = weighted_stake_timestamp(lp_user.warming_amount, lp_user.warmup_end, amount, ready_at)?;
+            lp_user.warming_amount = lp_user.warming_amount.checked_add(amount).ok_or(CustomError::MathOverflow)?;
+        } else {
+            lp_user.staked_amount = lp_user.staked_amount.checked_add(amount).ok_or(CustomError::MathOverflow)?;
+            state.lp_total_staked = state.lp_total_staked.checked_add(amount).ok_or(CustomError::MathOverflow)?;
+        }
         lp_user.reward_debt = (((lp_user.staked_amount as u128)
-            .checked_mul(state.acc_reward_per_share as u128)
+            .checked_mul(state.lp_acc_reward_per_share)
             .ok_or(CustomError::MathOverflow)?)
             / REWARD_MULTIPLIER as u128)
             .try_into()
             .unwrap();
-        lp_user.stake_timestamp = clock.unix_timestamp;
         lp_user.last_withdrawal_time = clock.unix_timestamp;
+        ctx.accounts.stake_history.record(state, clock.unix_timestamp)?;
+        emit!(LpStaked {
+            lp_user_stake: lp_user.key(),
+            user: ctx.accounts.user.key(),
+            amount,
+            staked_amount: lp_user.staked_amount,
+            warming_amount: lp_user.warming_amount,
+            lp_total_staked: state.lp_total_staked,
+        });
         Ok(())
     }
 
@@ -333,27 +1559,37 @@ pub mod perpetual_yield_token {
         let clock = Clock::get()?;
         let state = &mut ctx.accounts.global_state;
         let lp_user = &mut ctx.accounts.lp_user_stake;
-        require!(lp_user.staked_amount >= amount, CustomError::InsufficientStake);
         require!(
             clock.unix_timestamp - lp_user.last_withdrawal_time >= state.min_withdraw_interval,
             CustomError::WithdrawalTooFrequent
         );
+        update_reward_accrual(state, clock.unix_timestamp)?;
+        let promoted = lp_user.promote_warming(state.lp_acc_reward_per_share, clock.unix_timestamp)?;
+        state.lp_total_staked = state.lp_total_staked.checked_add(promoted).ok_or(CustomError::MathOverflow)?;
+        let total_balance = lp_user.staked_amount.checked_add(lp_user.warming_amount).ok_or(CustomError::MathOverflow)?;
+        require!(total_balance >= amount, CustomError::InsufficientStake);
         let accumulated = (lp_user.staked_amount as u128)
-            .checked_mul(state.acc_reward_per_share as u128)
+            .checked_mul(state.lp_acc_reward_per_share)
             .ok_or(CustomError::MathOverflow)?
             / REWARD_MULTIPLIER as u128;
         let pending = accumulated.checked_sub(lp_user.reward_debt as u128)
             .ok_or(CustomError::MathOverflow)?;
         lp_user.pending_rewards = lp_user.pending_rewards.checked_add(pending as u64)
             .ok_or(CustomError::MathOverflow)?;
-        lp_user.staked_amount = lp_user.staked_amount.checked_sub(amount).ok_or(CustomError::MathOverflow)?;
+        // Earning stake is withdrawn before warming stake, as in `unstake`.
+        let from_stake = amount.min(lp_user.staked_amount);
+        let from_warming = amount - from_stake;
+        lp_user.warming_amount = lp_user.warming_amount.checked_sub(from_warming).ok_or(CustomError::MathOverflow)?;
+        lp_user.staked_amount = lp_user.staked_amount.checked_sub(from_stake).ok_or(CustomError::MathOverflow)?;
+        state.lp_total_staked = state.lp_total_staked.checked_sub(from_stake).ok_or(CustomError::MathOverflow)?;
         lp_user.reward_debt = (((lp_user.staked_amount as u128)
-            .checked_mul(state.acc_reward_per_share as u128)
+            .checked_mul(state.lp_acc_reward_per_share)
             .ok_or(CustomError::MathOverflow)?)
             / REWARD_MULTIPLIER as u128)
             .try_into()
             .unwrap();
         lp_user.last_withdrawal_time = clock.unix_timestamp;
+        ctx.accounts.stake_history.record(state, clock.unix_timestamp)?;
         token::transfer(
             CpiContext::new(
                 ctx.accounts.token_program.to_account_info(),
@@ -366,6 +1602,12 @@ pub mod perpetual_yield_token {
             .with_signer(&[&[b"vault", &[ctx.bumps.vault_authority]]]),
             amount,
         )?;
+        emit!(LpUnstaked {
+            lp_user_stake: lp_user.key(),
+            amount,
+            staked_amount: lp_user.staked_amount,
+            lp_total_staked: state.lp_total_staked,
+        });
         Ok(())
     }
 
@@ -375,6 +1617,11 @@ pub mod perpetual_yield_token {
         let state = &mut ctx.accounts.global_state;
         let lp_user = &mut ctx.accounts.lp_user_stake;
         require!(verify_mev_proof(&proof), CustomError::InvalidMEVProof);
+        update_reward_accrual(state, clock.unix_timestamp)?;
+        let promoted = lp_user.promote_warming(state.lp_acc_reward_per_share, clock.unix_timestamp)?;
+        state.lp_total_staked = state.lp_total_staked.checked_add(promoted).ok_or(CustomError::MathOverflow)?;
+        ctx.accounts.stake_history.record(state, clock.unix_timestamp)?;
+        refresh_utilization(state, clock.unix_timestamp)?;
         require!(
             clock.unix_timestamp - lp_user.stake_timestamp >= state.cooldown_period,
             CustomError::StakePeriodTooShort
@@ -384,26 +1631,38 @@ pub mod perpetual_yield_token {
             CustomError::ClaimTooSoon
         );
         let accumulated = (lp_user.staked_amount as u128)
-            .checked_mul(state.acc_reward_per_share as u128)
+            .checked_mul(state.lp_acc_reward_per_share)
             .ok_or(CustomError::MathOverflow)?
             / REWARD_MULTIPLIER as u128;
         let pending_from_stake = accumulated.checked_sub(lp_user.reward_debt as u128)
             .ok_or(CustomError::MathOverflow)?;
-        let mut total_reward = lp_user.pending_rewards.checked_add(pending_from_stake as u64)
+        let base_reward = lp_user.pending_rewards.checked_add(pending_from_stake as u64)
             .ok_or(CustomError::MathOverflow)?;
-        let staked_duration = clock.unix_timestamp - lp_user.stake_timestamp;
-        let time_multiplier = if staked_duration < THIRTY_DAYS { 100 }
-                              else if staked_duration < NINETY_DAYS { 120 }
-                              else { 150 };
-        total_reward = total_reward.checked_mul(time_multiplier).ok_or(CustomError::MathOverflow)? / 100;
-        total_reward = total_reward.checked_mul(LP_BONUS_MULTIPLIER).ok_or(CustomError::MathOverflow)? / 100;
-        total_reward = total_reward.checked_mul(state.utilization_multiplier).ok_or(CustomError::MathOverflow)? / 100;
-        let rebate = calculate_rebate(lp_user.trade_volume_7d);
-        total_reward = total_reward.checked_mul(100 + rebate).ok_or(CustomError::MathOverflow)? / 100;
-        require!(total_reward > 0, CustomError::NoRewards);
+        let quote = price_claim(
+            state,
+            &ctx.accounts.rebate_config,
+            base_reward,
+            lp_user.stake_timestamp,
+            lp_user.trade_volume_7d,
+            LP_BONUS_MULTIPLIER,
+            clock.unix_timestamp,
+        )?;
+        require!(quote.total_reward > 0 || lp_user.owed_rewards > 0, CustomError::NoRewards);
+        let payout = pay_claim(state, &mut lp_user.owed_rewards, quote.total_reward, ctx.accounts.reward_vault.amount)?;
         lp_user.pending_rewards = 0;
+        emit!(LpRewardsClaimed {
+            lp_user_stake: lp_user.key(),
+            base_reward: quote.base_reward,
+            time_multiplier: quote.time_multiplier,
+            lp_bonus_multiplier: quote.lp_bonus_multiplier,
+            utilization_multiplier: quote.utilization_multiplier,
+            rebate: quote.rebate,
+            total_reward: quote.total_reward,
+            payout,
+            owed_rewards: lp_user.owed_rewards,
+        });
         lp_user.reward_debt = (((lp_user.staked_amount as u128)
-            .checked_mul(state.acc_reward_per_share as u128)
+            .checked_mul(state.lp_acc_reward_per_share)
             .ok_or(CustomError::MathOverflow)?)
             / REWARD_MULTIPLIER as u128)
             .try_into()
@@ -418,8 +1677,239 @@ pub mod perpetual_yield_token {
                 },
             )
             .with_signer(&[&[b"vault", &[ctx.bumps.vault_authority]]]),
-            total_reward,
+            payout,
+        )?;
+        Ok(())
+    }
+
+    /// Open a proposal. Voting power is measured at the submission time.
+    pub fn submit_proposal(
+        ctx: Context<SubmitProposal>,
+        proposal_id: u64,
+        proposal_data: String,
+        spend: Option<SpendAction>,
+    ) -> Result<()> {
+        require!(proposal_data.len() <= MAX_PROPOSAL_DATA_LEN, CustomError::ProposalDataTooLong);
+        require!(
+            spend.as_ref().is_none_or(|spend| spend.memo.len() <= MAX_SPEND_MEMO_LEN),
+            CustomError::ProposalDataTooLong
+        );
+        let clock = Clock::get()?;
+        let config = ctx.accounts.global_state.proposal_config.clone();
+        if config.deposit > 0 {
+            let from = ctx.accounts.proposer_token_account.as_ref().ok_or(CustomError::MissingDepositAccount)?;
+            let to = ctx.accounts.deposit_vault.as_ref().ok_or(CustomError::MissingDepositAccount)?;
+            token::transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    Transfer {
+                        from: from.to_account_info(),
+                        to: to.to_account_info(),
+                        authority: ctx.accounts.proposer.to_account_info(),
+                    },
+                ),
+                config.deposit,
+            )?;
+        }
+        let ve_state = &mut ctx.accounts.ve_state;
+        ve_state.checkpoint(clock.unix_timestamp)?;
+        let voting_start = clock.unix_timestamp
+            .checked_add(config.voting_delay)
+            .ok_or(CustomError::MathOverflow)?;
+        let proposal = &mut ctx.accounts.proposal;
+        proposal.proposal_id = proposal_id;
+        proposal.proposer = ctx.accounts.proposer.key();
+        proposal.proposal_data = proposal_data;
+        proposal.snapshot_timestamp = clock.unix_timestamp;
+        proposal.vote_count = 0;
+        proposal.against_count = 0;
+        proposal.executed = false;
+        proposal.total_voting_power = ve_state.total_power();
+        proposal.global_state = ctx.accounts.global_state.key();
+        proposal.deposit = config.deposit;
+        proposal.voting_start = voting_start;
+        proposal.voting_end = voting_start
+            .checked_add(config.voting_period)
+            .ok_or(CustomError::MathOverflow)?;
+        proposal.quorum = (proposal.total_voting_power as u128 * config.quorum_bps as u128 / 10_000) as u64;
+        proposal.slash_quorum = (proposal.total_voting_power as u128 * config.slash_quorum_bps as u128 / 10_000) as u64;
+        proposal.expires_at = proposal.voting_end
+            .checked_add(config.execution_window)
+            .ok_or(CustomError::MathOverflow)?;
+        proposal.spend = spend;
+        proposal.spend_paid = false;
+        emit!(ProposalSubmitted {
+            proposal: proposal.key(),
+            proposer: proposal.proposer,
+            proposal_id,
+            deposit: proposal.deposit,
+            voting_start: proposal.voting_start,
+            voting_end: proposal.voting_end,
+            quorum: proposal.quorum,
+        });
+        Ok(())
+    }
+
+    /// Mark a proposal executed once voting has ended with at least `quorum` votes for it
+    /// and more votes for than against.
+    pub fn execute_proposal(ctx: Context<ExecuteProposal>) -> Result<()> {
+        let clock = Clock::get()?;
+        let proposal = &mut ctx.accounts.proposal;
+        require!(!proposal.executed, CustomError::ProposalAlreadyExecuted);
+        require!(clock.unix_timestamp >= proposal.voting_end, CustomError::VotingNotEnded);
+        require!(clock.unix_timestamp < proposal.expires_at, CustomError::ProposalExpired);
+        require!(
+            proposal.vote_count > 0
+                && proposal.vote_count >= proposal.quorum
+                && proposal.vote_count > proposal.against_count,
+            CustomError::ProposalNotPassed
+        );
+        proposal.executed = true;
+        emit!(ProposalExecuted {
+            proposal: proposal.key(),
+            vote_count: proposal.vote_count,
+            against_count: proposal.against_count,
+        });
+        Ok(())
+    }
+
+    /// Pay out the treasury spend carried by an executed proposal.
+    pub fn treasury_spend(ctx: Context<TreasurySpend>) -> Result<()> {
+        let proposal = &mut ctx.accounts.proposal;
+        require!(proposal.executed, CustomError::ProposalNotPassed);
+        require!(!proposal.spend_paid, CustomError::ProposalAlreadyExecuted);
+        let spend = proposal.spend.clone().ok_or(CustomError::NoSpendAction)?;
+        require_keys_eq!(ctx.accounts.recipient.key(), spend.recipient, CustomError::NoSpendAction);
+        let global_key = ctx.accounts.global_state.key();
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.treasury_vault.to_account_info(),
+                    to: ctx.accounts.recipient.to_account_info(),
+                    authority: ctx.accounts.treasury_authority.to_account_info(),
+                },
+            )
+            .with_signer(&[&[b"treasury", global_key.as_ref(), &[ctx.bumps.treasury_authority]]]),
+            spend.amount,
         )?;
+        proposal.spend_paid = true;
+        emit!(TreasurySpent {
+            proposal: proposal.key(),
+            recipient: spend.recipient,
+            amount: spend.amount,
+            memo: spend.memo,
+        });
+        Ok(())
+    }
+
+    /// Withdraw a proposal before voting starts, refunding the deposit and the rent.
+    pub fn cancel_proposal(ctx: Context<CancelProposal>) -> Result<()> {
+        let clock = Clock::get()?;
+        let proposal = &ctx.accounts.proposal;
+        require!(clock.unix_timestamp < proposal.voting_start, CustomError::VotingStarted);
+        if proposal.deposit > 0 {
+            let from = ctx.accounts.deposit_vault.as_ref().ok_or(CustomError::MissingDepositAccount)?;
+            let to = ctx.accounts.proposer_token_account.as_ref().ok_or(CustomError::MissingDepositAccount)?;
+            let global_key = ctx.accounts.global_state.key();
+            token::transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    Transfer {
+                        from: from.to_account_info(),
+                        to: to.to_account_info(),
+                        authority: ctx.accounts.deposit_authority.to_account_info(),
+                    },
+                )
+                .with_signer(&[&[b"proposal_deposits", global_key.as_ref(), &[ctx.bumps.deposit_authority]]]),
+                proposal.deposit,
+            )?;
+        }
+        emit!(ProposalCancelled { proposal: proposal.key() });
+        Ok(())
+    }
+
+    /// Close a proposal once it has been executed or its execution window has passed,
+    /// returning the rent to the proposer. The deposit is refunded, or sent to the
+    /// treasury if the proposal drew less than `slash_quorum` votes.
+    pub fn close_proposal(ctx: Context<CloseProposal>) -> Result<()> {
+        let clock = Clock::get()?;
+        let proposal = &ctx.accounts.proposal;
+        let settled = proposal.executed && (proposal.spend.is_none() || proposal.spend_paid);
+        require!(
+            settled || clock.unix_timestamp >= proposal.expires_at,
+            CustomError::ProposalStillActive
+        );
+        let slashed = proposal.deposit > 0 && proposal.vote_count < proposal.slash_quorum;
+        if proposal.deposit > 0 {
+            let to = if slashed {
+                ctx.accounts.treasury.as_ref().ok_or(CustomError::MissingPenaltyAccount)?.to_account_info()
+            } else {
+                ctx.accounts.proposer_token_account.as_ref().ok_or(CustomError::MissingDepositAccount)?.to_account_info()
+            };
+            let from = ctx.accounts.deposit_vault.as_ref().ok_or(CustomError::MissingDepositAccount)?;
+            let global_key = ctx.accounts.global_state.key();
+            token::transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    Transfer {
+                        from: from.to_account_info(),
+                        to,
+                        authority: ctx.accounts.deposit_authority.to_account_info(),
+                    },
+                )
+                .with_signer(&[&[b"proposal_deposits", global_key.as_ref(), &[ctx.bumps.deposit_authority]]]),
+                proposal.deposit,
+            )?;
+        }
+        emit!(ProposalClosed {
+            proposal: proposal.key(),
+            deposit: proposal.deposit,
+            slashed,
+        });
+        Ok(())
+    }
+
+    /// Cast a position's vote on a proposal with its vote-escrow power at the snapshot.
+    /// Only whoever held the vote at the snapshot (the delegate, or the owner if there was
+    /// none) may cast it, and the vote record account makes a second vote fail, so
+    /// delegation changes after the snapshot cannot double-count a position. `support`
+    /// picks the side the power is counted on.
+    pub fn vote_proposal(ctx: Context<VoteProposal>, support: bool) -> Result<()> {
+        let clock = Clock::get()?;
+        let proposal = &mut ctx.accounts.proposal;
+        let user = &ctx.accounts.user_stake;
+        require!(
+            clock.unix_timestamp >= proposal.voting_start && clock.unix_timestamp < proposal.voting_end,
+            CustomError::VotingClosed
+        );
+        let checkpoint = user
+            .checkpoint_at(proposal.snapshot_timestamp)
+            .ok_or(CustomError::SnapshotUnavailable)?;
+        let holder = if checkpoint.delegate == Pubkey::default() { user.owner } else { checkpoint.delegate };
+        require_keys_eq!(holder, ctx.accounts.voter.key(), CustomError::NotVoteHolder);
+        let weight = checkpoint.voting_power_at(proposal.snapshot_timestamp);
+        require!(weight > 0, CustomError::NoVotingPower);
+        if support {
+            proposal.vote_count = proposal.vote_count.checked_add(weight).ok_or(CustomError::MathOverflow)?;
+        } else {
+            proposal.against_count = proposal.against_count.checked_add(weight).ok_or(CustomError::MathOverflow)?;
+        }
+        let record = &mut ctx.accounts.vote_record;
+        record.proposal = proposal.key();
+        record.user_stake = user.key();
+        record.voter = holder;
+        record.weight = weight;
+        record.support = support;
+        emit!(ProposalVoted {
+            proposal: proposal.key(),
+            user_stake: user.key(),
+            voter: holder,
+            support,
+            weight,
+            vote_count: proposal.vote_count,
+            against_count: proposal.against_count,
+        });
         Ok(())
     }
 }
@@ -427,8 +1917,13 @@ pub mod perpetual_yield_token {
 pub(crate) fn _claim_rewards(ctx: &mut Context<ClaimRewards>, proof: String) -> Result<()> {
     let clock = Clock::get()?;
     let state = &mut ctx.accounts.global_state;
+    let pool = &mut ctx.accounts.pool;
     let user = &mut ctx.accounts.user_stake;
     require!(verify_mev_proof(&proof), CustomError::InvalidMEVProof);
+    update_reward_accrual(state, clock.unix_timestamp)?;
+    pool.sync(state)?;
+    user.promote_warming(state, pool, clock.unix_timestamp)?;
+    refresh_utilization(state, clock.unix_timestamp)?;
     require!(
         clock.unix_timestamp - user.stake_timestamp >= state.cooldown_period,
         CustomError::StakePeriodTooShort
@@ -437,30 +1932,33 @@ pub(crate) fn _claim_rewards(ctx: &mut Context<ClaimRewards>, proof: String) ->
         clock.unix_timestamp - state.last_fee_deposit_time >= state.min_claim_delay,
         CustomError::ClaimTooSoon
     );
-    let accumulated = (user.staked_amount as u128)
-        .checked_mul(state.acc_reward_per_share as u128)
-        .ok_or(CustomError::MathOverflow)?
-        / REWARD_MULTIPLIER as u128;
-    let pending_from_stake = accumulated.checked_sub(user.reward_debt as u128)
-        .ok_or(CustomError::MathOverflow)?;
-    let mut total_reward = user.pending_rewards.checked_add(pending_from_stake as u64)
-        .ok_or(CustomError::MathOverflow)?;
-    let staked_duration = clock.unix_timestamp - user.stake_timestamp;
-    let time_multiplier = if staked_duration < THIRTY_DAYS { 100 }
-                          else if staked_duration < NINETY_DAYS { 120 }
-                          else { 150 };
-    total_reward = total_reward.checked_mul(time_multiplier).ok_or(CustomError::MathOverflow)? / 100;
-    total_reward = total_reward.checked_mul(state.utilization_multiplier).ok_or(CustomError::MathOverflow)? / 100;
-    let rebate = calculate_rebate(user.trade_volume_7d);
-    total_reward = total_reward.checked_mul(100 + rebate).ok_or(CustomError::MathOverflow)? / 100;
-    require!(total_reward > 0, CustomError::NoRewards);
+    user.settle_rewards(pool.acc_reward_per_share)?;
+    user.update_shares(state, pool, clock.unix_timestamp)?;
+    ctx.accounts.stake_history.record(state, clock.unix_timestamp)?;
+    let quote = price_claim(
+        state,
+        &ctx.accounts.rebate_config,
+        user.pending_rewards,
+        user.stake_timestamp,
+        user.trade_volume_7d,
+        100,
+        clock.unix_timestamp,
+    )?;
+    require!(quote.total_reward > 0 || user.owed_rewards > 0, CustomError::NoRewards);
+    let payout = pay_claim(state, &mut user.owed_rewards, quote.total_reward, ctx.accounts.reward_vault.amount)?;
     user.pending_rewards = 0;
-    user.reward_debt = (((user.staked_amount as u128)
-        .checked_mul(state.acc_reward_per_share as u128)
-        .ok_or(CustomError::MathOverflow)?)
-        / REWARD_MULTIPLIER as u128)
-        .try_into()
-        .unwrap();
+    emit!(RewardsClaimed {
+        user_stake: user.key(),
+        owner: user.owner,
+        pool: pool.key(),
+        base_reward: quote.base_reward,
+        time_multiplier: quote.time_multiplier,
+        utilization_multiplier: quote.utilization_multiplier,
+        rebate: quote.rebate,
+        total_reward: quote.total_reward,
+        payout,
+        owed_rewards: user.owed_rewards,
+    });
     token::transfer(
         CpiContext::new(
             ctx.accounts.token_program.to_account_info(),
@@ -471,25 +1969,389 @@ pub(crate) fn _claim_rewards(ctx: &mut Context<ClaimRewards>, proof: String) ->
             },
         )
         .with_signer(&[&[b"vault", &[ctx.bumps.vault_authority]]]),
-        total_reward,
+        payout,
     )?;
     Ok(())
 }
 
-fn verify_mev_proof(proof: &str) -> bool {
-    !proof.is_empty()
+/// Rewards `amount` staked tokens are entitled to at accumulator value `acc_reward_per_share`.
+fn accrued_rewards(amount: u64, acc_reward_per_share: u128) -> Result<u64> {
+    Ok((((amount as u128)
+        .checked_mul(acc_reward_per_share)
+        .ok_or(CustomError::MathOverflow)?)
+        / REWARD_MULTIPLIER as u128)
+        .try_into()
+        .map_err(|_| CustomError::MathOverflow)?)
 }
 
-fn calculate_rebate(trade_volume: u64) -> u64 {
-    if trade_volume <= 10_000 {
-        0
-    } else if trade_volume <= 100_000 {
-        5
-    } else if trade_volume <= 1_000_000 {
-        10
+/// Balance-weighted average of an existing stake age and a deposit of `added` made at `now`,
+/// so a top-up only dilutes seniority in proportion to its size. Also used to blend
+/// warm-up end times.
+fn weighted_stake_timestamp(balance: u64, stake_timestamp: i64, added: u64, now: i64) -> Result<i64> {
+    let total = (balance as i128).checked_add(added as i128).ok_or(CustomError::MathOverflow)?;
+    if balance == 0 || total == 0 {
+        return Ok(now);
+    }
+    let weighted = (stake_timestamp as i128)
+        .checked_mul(balance as i128)
+        .and_then(|old| (now as i128).checked_mul(added as i128).and_then(|new| old.checked_add(new)))
+        .ok_or(CustomError::MathOverflow)?;
+    Ok((weighted / total) as i64)
+}
+
+/// Spread `amount` over every reward share via `acc_reward_per_share` and record it as a
+/// liability. With nothing staked the amount is left unallocated in the vault.
+fn allocate_rewards(state: &mut GlobalState, amount: u64) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    if state.total_gauge_weight > 0 {
+        return allocate_gauge_rewards(state, amount);
+    }
+    // Without gauge weights, PYT reward shares and LP stake share one accumulator, so
+    // both back the allocation.
+    let reward_base = state.total_reward_shares.checked_add(state.lp_total_staked).ok_or(CustomError::MathOverflow)?;
+    if reward_base == 0 {
+        return Ok(());
+    }
+    let add_amount = (amount as u128)
+        .checked_mul(REWARD_MULTIPLIER as u128)
+        .ok_or(CustomError::MathOverflow)?
+        / reward_base as u128;
+    state.acc_reward_per_share = state.acc_reward_per_share.checked_add(add_amount).ok_or(CustomError::MathOverflow)?;
+    state.lp_acc_reward_per_share = state.lp_acc_reward_per_share.checked_add(add_amount).ok_or(CustomError::MathOverflow)?;
+    state.total_reward_liabilities = state.total_reward_liabilities
+        .checked_add(amount)
+        .ok_or(CustomError::MathOverflow)?;
+    Ok(())
+}
+
+/// Split `amount` across the gauges by their applied weight. The LP share is credited
+/// straight away; pools collect theirs from `acc_reward_per_weight` when next synced.
+fn allocate_gauge_rewards(state: &mut GlobalState, amount: u64) -> Result<()> {
+    let total_weight = state.total_gauge_weight as u128;
+    let lp_portion = (amount as u128 * state.lp_gauge_weight as u128 / total_weight) as u64;
+    let mut allocated = amount;
+    if state.lp_total_staked > 0 {
+        let add_amount = lp_portion as u128 * REWARD_MULTIPLIER as u128 / state.lp_total_staked as u128;
+        state.lp_acc_reward_per_share = state.lp_acc_reward_per_share.checked_add(add_amount).ok_or(CustomError::MathOverflow)?;
     } else {
-        15
+        // Nobody to pay; the LP share stays in the vault unallocated.
+        allocated -= lp_portion;
     }
+    let add_per_weight = (amount as u128)
+        .checked_mul(GAUGE_PRECISION)
+        .ok_or(CustomError::MathOverflow)?
+        / total_weight;
+    state.acc_reward_per_weight = state.acc_reward_per_weight.checked_add(add_per_weight).ok_or(CustomError::MathOverflow)?;
+    state.total_reward_liabilities = state.total_reward_liabilities
+        .checked_add(allocated)
+        .ok_or(CustomError::MathOverflow)?;
+    Ok(())
+}
+
+/// Release the streamed rewards emitted since `last_update_time` into the accumulator.
+///
+/// Must run before any change to `total_staked` / `lp_total_staked` so the elapsed
+/// period is split over the balances that were actually staked during it.
+fn update_reward_accrual(state: &mut GlobalState, now: i64) -> Result<()> {
+    let applicable = now.min(state.period_finish);
+    if applicable > state.last_update_time {
+        let streamed = ((applicable - state.last_update_time) as u128)
+            .checked_mul(state.reward_rate)
+            .and_then(|streamed| streamed.checked_add(state.reward_remainder))
+            .ok_or(CustomError::MathOverflow)?;
+        // Fractions of a token are carried to the next update instead of being dropped.
+        state.reward_remainder = streamed % REWARD_MULTIPLIER as u128;
+        let emitted: u64 = (streamed / REWARD_MULTIPLIER as u128)
+            .try_into()
+            .map_err(|_| CustomError::MathOverflow)?;
+        allocate_rewards(state, emitted)?;
+        state.last_update_time = applicable;
+    }
+    Ok(())
+}
+
+/// Hand `amount` to stakers: allocated at once when `reward_duration` is zero, otherwise
+/// folded together with the rest of the running stream into a new `reward_duration` stream.
+fn queue_rewards(state: &mut GlobalState, amount: u64, now: i64) -> Result<()> {
+    update_reward_accrual(state, now)?;
+    if state.reward_duration == 0 {
+        return allocate_rewards(state, amount);
+    }
+    if amount == 0 {
+        return Ok(());
+    }
+    // Both sides stay scaled by `REWARD_MULTIPLIER`, so no remainder is stranded.
+    let leftover = if now < state.period_finish {
+        ((state.period_finish - now) as u128)
+            .checked_mul(state.reward_rate)
+            .ok_or(CustomError::MathOverflow)?
+    } else {
+        0
+    };
+    state.reward_rate = (amount as u128)
+        .checked_mul(REWARD_MULTIPLIER as u128)
+        .and_then(|scaled| scaled.checked_add(leftover))
+        .and_then(|scaled| scaled.checked_add(state.reward_remainder))
+        .ok_or(CustomError::MathOverflow)?
+        / state.reward_duration as u128;
+    state.reward_remainder = 0;
+    state.last_update_time = now;
+    state.period_finish = now
+        .checked_add(state.reward_duration)
+        .ok_or(CustomError::MathOverflow)?;
+    Ok(())
+}
+
+/// Apply the claim multipliers to `base_reward` and settle the result against the bonus
+/// budget via `fund_claim`. `lp_bonus_multiplier` is 100 for PYT stake. The returned quote
+/// leaves `payout` and `owed_rewards` for the caller to fill in from `pay_claim`.
+fn price_claim(
+    state: &mut GlobalState,
+    rebate_config: &RebateConfig,
+    base_reward: u64,
+    stake_timestamp: i64,
+    trade_volume: u64,
+    lp_bonus_multiplier: u64,
+    now: i64,
+) -> Result<RewardQuote> {
+    let time_multiplier = time_multiplier(state, now - stake_timestamp);
+    let rebate = rebate_config.rebate(trade_volume);
+    let mut gross_reward = base_reward;
+    gross_reward = gross_reward.checked_mul(time_multiplier).ok_or(CustomError::MathOverflow)? / 100;
+    gross_reward = gross_reward.checked_mul(lp_bonus_multiplier).ok_or(CustomError::MathOverflow)? / 100;
+    gross_reward = gross_reward.checked_mul(state.utilization_multiplier).ok_or(CustomError::MathOverflow)? / 100;
+    gross_reward = gross_reward.checked_mul(100 + rebate).ok_or(CustomError::MathOverflow)? / 100;
+    let total_reward = fund_claim(state, base_reward, gross_reward)?;
+    // Both gates must clear; the fee-deposit gate moves again with every new deposit.
+    let claimable_at = stake_timestamp
+        .saturating_add(state.cooldown_period)
+        .max(state.last_fee_deposit_time.saturating_add(state.min_claim_delay));
+    Ok(RewardQuote {
+        base_reward,
+        time_multiplier,
+        lp_bonus_multiplier,
+        utilization_multiplier: state.utilization_multiplier,
+        rebate,
+        total_reward,
+        payout: 0,
+        owed_rewards: 0,
+        claimable_at,
+    })
+}
+
+/// Settle a claim against the reward liabilities and the bonus budget.
+///
+/// `base_reward` is what the accumulator allocated to the position and is released from
+/// `total_reward_liabilities`. Anything the multipliers add on top is paid from
+/// `bonus_budget`, capped at what is left in it; a haircut below the base (e.g. a
+/// utilization multiplier under 100) is returned to the budget. Returns the amount earned.
+fn fund_claim(state: &mut GlobalState, base_reward: u64, gross_reward: u64) -> Result<u64> {
+    state.total_reward_liabilities = state.total_reward_liabilities.saturating_sub(base_reward);
+    let payout = if gross_reward >= base_reward {
+        let bonus = (gross_reward - base_reward).min(state.bonus_budget);
+        state.bonus_budget -= bonus;
+        base_reward.checked_add(bonus).ok_or(CustomError::MathOverflow)?
+    } else {
+        state.bonus_budget = state.bonus_budget
+            .checked_add(base_reward - gross_reward)
+            .ok_or(CustomError::MathOverflow)?;
+        gross_reward
+    };
+    Ok(payout)
+}
+
+/// Work out how much of a claim can actually leave the reward vault.
+///
+/// Rewards previously recorded as owed are paid first, out of `owed_reserve` (the part of
+/// later fee deposits set aside for IOUs). The newly earned `reward` is then paid from the
+/// vault's distributable balance; whatever the vault cannot cover is added to the
+/// position's `owed_rewards` and to `total_owed`. Returns the amount to transfer.
+fn pay_claim(state: &mut GlobalState, owed_rewards: &mut u64, reward: u64, vault_balance: u64) -> Result<u64> {
+    let repaid = (*owed_rewards).min(state.owed_reserve);
+    *owed_rewards -= repaid;
+    state.owed_reserve -= repaid;
+    state.total_owed = state.total_owed.saturating_sub(repaid);
+
+    let distributable = vault_balance
+        .saturating_sub(state.insurance_fund)
+        .saturating_sub(state.owed_reserve)
+        .saturating_sub(repaid);
+    let paid = reward.min(distributable);
+    let shortfall = reward - paid;
+    if shortfall > 0 {
+        *owed_rewards = owed_rewards.checked_add(shortfall).ok_or(CustomError::MathOverflow)?;
+        state.total_owed = state.total_owed.checked_add(shortfall).ok_or(CustomError::MathOverflow)?;
+    }
+    Ok(repaid.checked_add(paid).ok_or(CustomError::MathOverflow)?)
+}
+
+/// Close out the fee window if it has elapsed. The last completed window becomes the
+/// trailing sample; if more than one window passed without deposits the sample is zero.
+fn roll_utilization_window(state: &mut GlobalState, now: i64) {
+    let elapsed = now - state.utilization_window_start;
+    if elapsed < state.utilization_window {
+        return;
+    }
+    state.trailing_fee_total = if elapsed < state.utilization_window * 2 {
+        state.window_fee_total
+    } else {
+        0
+    };
+    state.window_fee_total = 0;
+    state.utilization_window_start = now;
+}
+
+/// Recompute `utilization_multiplier` from recent fee deposits when running in on-chain mode.
+///
+/// The fee yield of the trailing window (or the window in progress, whichever is larger)
+/// is compared against `target_fee_yield_bps`: hitting the target yields 100, falling short
+/// scales the multiplier down. The governance adjustment is applied before clamping.
+fn refresh_utilization(state: &mut GlobalState, now: i64) -> Result<()> {
+    roll_utilization_window(state, now);
+    if state.utilization_mode != UtilizationMode::OnChain {
+        return Ok(());
+    }
+    let derived: u64 = if state.total_staked == 0 {
+        state.max_utilization_multiplier
+    } else {
+        let fees = state.trailing_fee_total.max(state.window_fee_total) as u128;
+        let fee_yield_bps = fees
+            .checked_mul(10_000)
+            .ok_or(CustomError::MathOverflow)?
+            / state.total_staked as u128;
+        let scaled = fee_yield_bps
+            .checked_mul(100)
+            .ok_or(CustomError::MathOverflow)?
+            / state.target_fee_yield_bps as u128;
+        scaled.min(u64::MAX as u128) as u64
+    };
+    let adjusted = (derived.min(i64::MAX as u64) as i64).saturating_add(state.utilization_adjustment);
+    state.utilization_multiplier = (adjusted.max(0) as u64)
+        .clamp(state.min_utilization_multiplier, state.max_utilization_multiplier);
+    Ok(())
+}
+
+fn verify_mev_proof(proof: &str) -> bool {
+    !proof.is_empty()
+}
+
+fn validate_multiplier_schedule(schedule: &[MultiplierBreakpoint]) -> Result<()> {
+    require!(
+        schedule.len() <= MAX_MULTIPLIER_BREAKPOINTS,
+        CustomError::InvalidMultiplierSchedule
+    );
+    require!(
+        schedule.iter().all(|point| point.min_duration >= 0 && point.multiplier > 0),
+        CustomError::InvalidMultiplierSchedule
+    );
+    require!(
+        schedule.windows(2).all(|pair| {
+            pair[0].min_duration < pair[1].min_duration && pair[0].multiplier <= pair[1].multiplier
+        }),
+        CustomError::InvalidMultiplierSchedule
+    );
+    Ok(())
+}
+
+/// Loyalty multiplier (100 = 1x) for stake held `staked_duration` seconds. Durations before
+/// the first breakpoint, or an empty schedule, get 100.
+fn time_multiplier(state: &GlobalState, staked_duration: i64) -> u64 {
+    let schedule = &state.time_multiplier_schedule;
+    let reached = schedule.iter().take_while(|point| point.min_duration <= staked_duration).count();
+    if reached == 0 {
+        return 100;
+    }
+    let current = schedule[reached - 1];
+    match schedule.get(reached) {
+        Some(next) if state.interpolate_time_multiplier => {
+            let span = (next.min_duration - current.min_duration) as u128;
+            let elapsed = (staked_duration - current.min_duration) as u128;
+            let rise = (next.multiplier - current.multiplier) as u128;
+            current.multiplier + (rise * elapsed / span) as u64
+        }
+        _ => current.multiplier,
+    }
+}
+
+/// Voting power at `t` of a lock losing `slope` per second until `lock_end`.
+fn ve_power(slope: u128, lock_end: i64, t: i64) -> u64 {
+    if t >= lock_end {
+        return 0;
+    }
+    (slope * (lock_end - t) as u128 / VE_PRECISION) as u64
+}
+
+fn set_delegate(user: &mut Account<UserStake>, delegate: Pubkey) -> Result<()> {
+    let clock = Clock::get()?;
+    let previous = user.delegate;
+    user.delegate = delegate;
+    user.write_checkpoint(clock.unix_timestamp);
+    emit!(DelegateChanged {
+        user_stake: user.key(),
+        owner: user.owner,
+        previous,
+        delegate,
+    });
+    Ok(())
+}
+
+/// Fail with `InvalidParameter`, logging which field was rejected.
+fn check_parameter(valid: bool, field: &str) -> Result<()> {
+    if !valid {
+        msg!("Invalid parameter: {}", field);
+        return err!(CustomError::InvalidParameter);
+    }
+    Ok(())
+}
+
+/// Install queued parameter limits once their delay has passed.
+fn apply_pending_limits(state: &mut GlobalState, now: i64) {
+    if now >= state.parameter_limits_effective_at {
+        if let Some(limits) = state.pending_parameter_limits.take() {
+            state.parameter_limits = limits;
+        }
+    }
+}
+
+/// Fail with `ParameterChangeTooLarge` if `field` moves by more than `limit` (0 = no limit).
+fn check_change(field: &str, old: i128, new: i128, limit: u64) -> Result<()> {
+    if limit != 0 && (new - old).unsigned_abs() > limit as u128 {
+        msg!("Parameter change too large: {}", field);
+        return err!(CustomError::ParameterChangeTooLarge);
+    }
+    Ok(())
+}
+
+fn validate_multisig(signers: &[Pubkey], threshold: u8) -> Result<()> {
+    require!(
+        !signers.is_empty() && signers.len() <= MAX_MULTISIG_SIGNERS,
+        CustomError::InvalidMultisigConfig
+    );
+    require!(
+        threshold > 0 && threshold as usize <= signers.len(),
+        CustomError::InvalidMultisigConfig
+    );
+    let unique = signers
+        .iter()
+        .enumerate()
+        .all(|(i, signer)| !signers[..i].contains(signer));
+    require!(unique, CustomError::InvalidMultisigConfig);
+    Ok(())
+}
+
+fn validate_rebate_tiers(tiers: &[RebateTier]) -> Result<()> {
+    require!(tiers.len() <= MAX_REBATE_TIERS, CustomError::InvalidRebateTiers);
+    require!(
+        tiers.iter().all(|tier| tier.rebate <= MAX_REBATE_PERCENT),
+        CustomError::InvalidRebateTiers
+    );
+    require!(
+        tiers.windows(2).all(|pair| pair[0].min_volume < pair[1].min_volume),
+        CustomError::InvalidRebateTiers
+    );
+    Ok(())
 }
 
 #[error_code]
@@ -510,6 +2372,238 @@ pub enum CustomError {
     ClaimTooSoon,
     #[msg("No rewards available to claim.")]
     NoRewards,
+    #[msg("Utilization multiplier is derived on-chain; use a bounded adjustment instead.")]
+    UtilizationDerivedOnChain,
+    #[msg("Invalid utilization configuration.")]
+    InvalidUtilizationConfig,
+    #[msg("Utilization adjustment exceeds the allowed bound.")]
+    UtilizationAdjustmentTooLarge,
+    #[msg("Reward duration must not be negative.")]
+    InvalidRewardDuration,
+    #[msg("Warm-up period must not be negative.")]
+    InvalidWarmupPeriod,
+    #[msg("Penalty split must not exceed 10,000 basis points.")]
+    InvalidPenaltySplit,
+    #[msg("The account required to route this penalty was not provided.")]
+    MissingPenaltyAccount,
+    #[msg("Position is staked in a different pool; use extend_lockup to switch.")]
+    PoolMismatch,
+    #[msg("Target pool's lockup is shorter than the current one.")]
+    LockupNotExtended,
+    #[msg("Pool has been retired.")]
+    PoolRetired,
+    #[msg("Pool has not been retired.")]
+    PoolNotRetired,
+    #[msg("Time multiplier schedule must be ordered and non-decreasing.")]
+    InvalidMultiplierSchedule,
+    #[msg("Rebate tiers must be strictly ascending and within the rebate cap.")]
+    InvalidRebateTiers,
+    #[msg("Invalid vote-escrow configuration.")]
+    InvalidVeConfig,
+    #[msg("Lock must end in the future, within the maximum lock, and not earlier than before.")]
+    InvalidVeLock,
+    #[msg("Stake is locked in vote escrow.")]
+    VeLocked,
+    #[msg("No checkpoint covers the requested time.")]
+    SnapshotUnavailable,
+    #[msg("Signer did not hold this position's vote at the proposal snapshot.")]
+    NotVoteHolder,
+    #[msg("Delegate must be a real key; use undelegate to clear it.")]
+    InvalidDelegate,
+    #[msg("No voting power at the proposal snapshot.")]
+    NoVotingPower,
+    #[msg("Invalid proposal configuration.")]
+    InvalidProposalConfig,
+    #[msg("Proposal data is too long.")]
+    ProposalDataTooLong,
+    #[msg("Voting on this proposal has already started.")]
+    VotingStarted,
+    #[msg("Proposal is not open for voting.")]
+    VotingClosed,
+    #[msg("Proposal can still be voted on or executed.")]
+    ProposalStillActive,
+    #[msg("Voting on this proposal has not ended yet.")]
+    VotingNotEnded,
+    #[msg("Proposal's execution window has passed.")]
+    ProposalExpired,
+    #[msg("Proposal did not reach quorum or has not been executed.")]
+    ProposalNotPassed,
+    #[msg("Proposal has already been executed.")]
+    ProposalAlreadyExecuted,
+    #[msg("Proposal carries no matching treasury spend.")]
+    NoSpendAction,
+    #[msg("Multisig needs 1 to 10 distinct signers and a threshold no larger than the set.")]
+    InvalidMultisigConfig,
+    #[msg("Signer is not part of the multisig.")]
+    NotMultisigSigner,
+    #[msg("Governance action has too many accounts or too much data.")]
+    GovernanceActionTooLarge,
+    #[msg("Multisig signers changed after this action was proposed.")]
+    StaleGovernanceAction,
+    #[msg("Governance action has not reached the multisig threshold.")]
+    NotEnoughApprovals,
+    #[msg("A parameter is outside its allowed range; the program log names the field.")]
+    InvalidParameter,
+    #[msg("A parameter change exceeds the governance limit; the program log names the field.")]
+    ParameterChangeTooLarge,
+    #[msg("Signer is not the pending authority for this transfer.")]
+    NotPendingAuthority,
+    #[msg("Treasury and insurance cuts together must not exceed 10,000 basis points.")]
+    InvalidTreasuryFee,
+    #[msg("The treasury account is required for the protocol fee cut.")]
+    MissingTreasuryAccount,
+    #[msg("The proposer token account and deposit vault are required when a deposit is held.")]
+    MissingDepositAccount,
+    #[msg("Gauge target must be a pool of this program or the global state for LP.")]
+    InvalidGaugeTarget,
+    #[msg("Gauge votes exceed the position's voting power for this epoch.")]
+    GaugeVoteExceeded,
+    #[msg("No two deposit lots from the same pool are left to merge.")]
+    TooManyStakeLots,
+    #[msg("The treasury vault must be initialized first.")]
+    TreasuryNotInitialized,
+    #[msg("The stake history page is full; open the next one.")]
+    StakeHistoryPageFull,
+    #[msg("No more gauges can be created.")]
+    TooManyGauges,
+    #[msg("Every gauge must be passed exactly once to checkpoint the epoch.")]
+    IncompleteGaugeCheckpoint,
+}
+
+/// Source of the utilization multiplier applied to claims.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UtilizationMode {
+    /// Governance sets `utilization_multiplier` directly.
+    #[default]
+    Manual,
+    /// Derived from trailing fee deposits versus `total_staked`, clamped to configured bounds.
+    OnChain,
+}
+
+/// Claims on stake held at least `min_duration` seconds use `multiplier` (100 = 1x).
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct MultiplierBreakpoint {
+    pub min_duration: i64,
+    pub multiplier: u64,
+}
+
+/// Trade volume strictly above `min_volume` earns `rebate` percent on claims.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct RebateTier {
+    pub min_volume: u64,
+    pub rebate: u64,
+}
+
+/// Governance-managed trade-volume rebate schedule.
+#[account]
+pub struct RebateConfig {
+    pub global_state: Pubkey,
+    /// Tiers in strictly ascending `min_volume` order.
+    pub tiers: Vec<RebateTier>,
+    pub bump: u8,
+}
+
+impl RebateConfig {
+    /// The original 10k/100k/1M schedule.
+    pub fn default_tiers() -> Vec<RebateTier> {
+        vec![
+            RebateTier { min_volume: 10_000, rebate: 5 },
+            RebateTier { min_volume: 100_000, rebate: 10 },
+            RebateTier { min_volume: 1_000_000, rebate: 15 },
+        ]
+    }
+
+    /// Rebate percent for `trade_volume`: the highest tier it exceeds, or 0.
+    pub fn rebate(&self, trade_volume: u64) -> u64 {
+        self.tiers
+            .iter()
+            .take_while(|tier| trade_volume > tier.min_volume)
+            .last()
+            .map_or(0, |tier| tier.rebate)
+    }
+}
+
+/// A single deposit into a position, kept so lockups apply per deposit.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct StakeLot {
+    pub amount: u64,
+    pub timestamp: i64,
+    /// Pool the deposit was locked in.
+    pub pool: Pubkey,
+}
+
+/// Shape of the early-withdrawal penalty over a pool's lockup.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PenaltyCurve {
+    /// Full penalty until the lockup ends.
+    #[default]
+    Cliff,
+    /// Falls linearly from the full penalty to zero over the lockup.
+    Linear,
+    /// Falls to zero in `steps` equal drops over the lockup.
+    Stepped { steps: u8 },
+}
+
+impl PenaltyCurve {
+    /// Penalty in basis points for stake that has been locked for `elapsed` seconds.
+    pub fn penalty_bps(&self, elapsed: i64, lockup_period: i64, max_penalty_bps: u64) -> u64 {
+        if lockup_period <= 0 || elapsed >= lockup_period {
+            return 0;
+        }
+        let elapsed = elapsed.max(0) as u128;
+        let lockup = lockup_period as u128;
+        let max = max_penalty_bps as u128;
+        let bps = match *self {
+            PenaltyCurve::Cliff => max,
+            PenaltyCurve::Linear => max * (lockup - elapsed) / lockup,
+            PenaltyCurve::Stepped { steps } => {
+                let steps = steps.max(1) as u128;
+                let completed = elapsed * steps / lockup;
+                max * (steps - completed) / steps
+            }
+        };
+        bps as u64
+    }
+}
+
+/// Destination for a pool's early-withdrawal penalties.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PenaltyDestination {
+    /// Kept in the reward vault and counted towards `insurance_fund`.
+    #[default]
+    Insurance,
+    /// Streamed to the remaining stakers through `acc_reward_per_share`.
+    Redistribute,
+    /// Sent to the `GlobalState.treasury` token account.
+    Treasury,
+    /// Burned from the staking vault.
+    Burn,
+}
+
+/// Penalty amounts gathered per destination during an unstake.
+#[derive(Default)]
+struct PenaltyRouting {
+    insurance: u64,
+    redistribute: u64,
+    treasury: u64,
+    burn: u64,
+}
+
+impl PenaltyRouting {
+    fn add(&mut self, pool: &PoolInfo, penalty: u64) -> Result<()> {
+        let routed: u64 = ((penalty as u128) * (pool.penalty_destination_bps as u128) / 10_000)
+            .try_into()
+            .map_err(|_| CustomError::MathOverflow)?;
+        let slot = match pool.penalty_destination {
+            PenaltyDestination::Insurance => &mut self.insurance,
+            PenaltyDestination::Redistribute => &mut self.redistribute,
+            PenaltyDestination::Treasury => &mut self.treasury,
+            PenaltyDestination::Burn => &mut self.burn,
+        };
+        *slot = slot.checked_add(routed).ok_or(CustomError::MathOverflow)?;
+        self.insurance = self.insurance.checked_add(penalty - routed).ok_or(CustomError::MathOverflow)?;
+        Ok(())
+    }
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
@@ -517,106 +2611,1748 @@ pub struct PoolInfo {
     pub lockup_period: i64,
     pub apr_multiplier: u64,
     pub transaction_fee: u64,
+    pub penalty_curve: PenaltyCurve,
+    /// Where early-withdrawal penalties from this pool go.
+    pub penalty_destination: PenaltyDestination,
+    /// Share of the penalty (in basis points) sent to `penalty_destination`; the rest goes
+    /// to the insurance fund.
+    pub penalty_destination_bps: u64,
 }
 
-#[account]
-pub struct GlobalState {
-    pub total_staked: u64,
-    pub acc_reward_per_share: u64,
-    pub token_mint: Pubkey,
+impl PoolInfo {
+    fn validate(&self, state: &GlobalState) -> Result<()> {
+        require!(self.lockup_period >= 0, CustomError::InvalidPoolType);
+        require!(self.penalty_destination_bps <= 10_000, CustomError::InvalidPenaltySplit);
+        // Treasury-bound penalties need a treasury vault to land in.
+        require!(
+            self.penalty_destination != PenaltyDestination::Treasury
+                || self.penalty_destination_bps == 0
+                || state.treasury != Pubkey::default(),
+            CustomError::TreasuryNotInitialized
+        );
+        Ok(())
+    }
+
+    /// Penalty in basis points for a deposit made at `deposited_at`.
+    pub fn penalty_bps(&self, deposited_at: i64, now: i64, max_penalty_bps: u64) -> u64 {
+        self.penalty_curve.penalty_bps(now - deposited_at, self.lockup_period, max_penalty_bps)
+    }
+}
+
+#[event]
+pub struct Initialized {
+    pub global_state: Pubkey,
     pub owner: Pubkey,
     pub governance: Pubkey,
-    pub cooldown_period: i64,
-    pub early_withdrawal_penalty: u64,
-    pub min_withdraw_interval: i64,
-    pub min_claim_delay: i64,
-    pub insurance_fee_percent: u64,
+    pub token_mint: Pubkey,
+    pub parameters: ProtocolParameters,
+}
+
+#[event]
+pub struct ParametersUpdated {
+    pub old: ProtocolParameters,
+    pub new: ProtocolParameters,
+}
+
+#[event]
+pub struct ParameterLimitsUpdated {
+    pub limits: ParameterLimits,
+    /// When `limits` take effect; later than now when they loosen the current limits.
+    pub effective_at: i64,
+}
+
+#[event]
+pub struct PoolCreated {
+    pub pool: Pubkey,
+    pub pool_id: u64,
+    pub info: PoolInfo,
+}
+
+#[event]
+pub struct PoolUpdated {
+    pub pool: Pubkey,
+    pub info: PoolInfo,
+}
+
+#[event]
+pub struct PoolRetired {
+    pub pool: Pubkey,
+}
+
+#[event]
+pub struct VeLockUpdated {
+    pub user_stake: Pubkey,
+    pub owner: Pubkey,
+    pub ve_amount: u64,
+    pub ve_lock_end: i64,
+    pub reward_shares: u64,
+}
+
+#[event]
+pub struct BonusBudgetFunded {
+    pub funder: Pubkey,
+    pub amount: u64,
+    pub bonus_budget: u64,
+}
+
+#[event]
+pub struct ProposalSubmitted {
+    pub proposal: Pubkey,
+    pub proposer: Pubkey,
+    pub proposal_id: u64,
+    pub deposit: u64,
+    pub voting_start: i64,
+    pub voting_end: i64,
+    pub quorum: u64,
+}
+
+#[event]
+pub struct ProposalExecuted {
+    pub proposal: Pubkey,
+    pub vote_count: u64,
+    pub against_count: u64,
+}
+
+#[event]
+pub struct ProposalCancelled {
+    pub proposal: Pubkey,
+}
+
+#[event]
+pub struct ProposalClosed {
+    pub proposal: Pubkey,
+    pub deposit: u64,
+    /// Whether the deposit went to the treasury instead of back to the proposer.
+    pub slashed: bool,
+}
+
+#[event]
+pub struct GaugeVoted {
+    pub gauge: Pubkey,
+    pub user_stake: Pubkey,
+    /// Epoch the votes will apply to.
+    pub epoch: u64,
+    pub weight_bps: u64,
+    pub power: u64,
+}
+
+#[event]
+pub struct GaugeWeightApplied {
+    pub gauge: Pubkey,
+    pub epoch: u64,
+    pub weight: u64,
+}
+
+#[event]
+pub struct GaugeCreated {
+    pub gauge: Pubkey,
+    pub target: Pubkey,
+}
+
+#[event]
+pub struct ProposalVoted {
+    pub proposal: Pubkey,
+    pub user_stake: Pubkey,
+    /// Owner or delegate that cast the vote.
+    pub voter: Pubkey,
+    pub support: bool,
+    pub weight: u64,
+    pub vote_count: u64,
+    pub against_count: u64,
+}
+
+#[event]
+pub struct RewardDurationUpdated {
+    pub reward_duration: i64,
+}
+
+#[event]
+pub struct WarmupPeriodUpdated {
+    pub warmup_period: i64,
+}
+
+/// A matured warm-up balance started earning. `position` is a `UserStake` or an
+/// `LPUserStake`.
+#[event]
+pub struct WarmupPromoted {
+    pub position: Pubkey,
+    pub amount: u64,
+    pub staked_amount: u64,
+}
+
+#[event]
+pub struct TreasuryInitialized {
+    pub global_state: Pubkey,
+    pub treasury: Pubkey,
+}
+
+#[event]
+pub struct TreasuryFeeUpdated {
+    pub treasury_fee_bps: u64,
+}
+
+#[event]
+pub struct VeInitialized {
+    pub global_state: Pubkey,
+    pub ve_state: Pubkey,
+}
+
+#[event]
+pub struct VeConfigured {
+    pub max_lock: i64,
+    pub max_boost: u64,
+}
+
+#[event]
+pub struct ProposalsConfigured {
+    pub config: ProposalConfig,
+}
+
+#[event]
+pub struct ProposalDepositsInitialized {
+    pub global_state: Pubkey,
+    pub deposit_vault: Pubkey,
+}
+
+#[event]
+pub struct TimeMultiplierScheduleUpdated {
+    pub schedule: Vec<MultiplierBreakpoint>,
+    pub interpolate: bool,
+}
+
+/// Emitted when the rebate tiers are created and whenever they are replaced.
+#[event]
+pub struct RebateTiersUpdated {
+    pub rebate_config: Pubkey,
+    pub tiers: Vec<RebateTier>,
+}
+
+/// Emitted when a multisig is created and whenever its signer set changes.
+#[event]
+pub struct MultisigConfigUpdated {
+    pub multisig: Pubkey,
+    pub signers: Vec<Pubkey>,
+    pub threshold: u8,
+    pub owner_set_seqno: u64,
+}
+
+#[event]
+pub struct GovernanceActionProposed {
+    pub action: Pubkey,
+    pub multisig: Pubkey,
+    pub proposer: Pubkey,
+}
+
+#[event]
+pub struct GovernanceActionApproved {
+    pub action: Pubkey,
+    pub signer: Pubkey,
+    pub approvals: u8,
+}
+
+#[event]
+pub struct GovernanceActionExecuted {
+    pub action: Pubkey,
+    pub multisig: Pubkey,
+}
+
+#[event]
+pub struct PositionOpened {
+    pub user_stake: Pubkey,
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+}
+
+#[event]
+pub struct StakeHistoryPageOpened {
+    pub stake_history: Pubkey,
+    pub page: u64,
+}
+
+#[event]
+pub struct Staked {
+    pub user_stake: Pubkey,
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub amount: u64,
+    pub staked_amount: u64,
+    pub warming_amount: u64,
+    pub total_staked: u64,
+}
+
+#[event]
+pub struct Unstaked {
+    pub user_stake: Pubkey,
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub amount: u64,
+    /// Early-withdrawal penalty withheld from `amount`.
+    pub penalty: u64,
+    pub received: u64,
+    pub staked_amount: u64,
+    pub total_staked: u64,
+}
+
+#[event]
+pub struct FeeDeposited {
+    pub depositor: Pubkey,
+    pub amount: u64,
+    pub treasury_cut: u64,
+    pub insurance_fee: u64,
+    /// Part of the deposit set aside to pay outstanding IOUs.
+    pub owed_repayment: u64,
+    /// Amount queued for stakers after every cut.
+    pub distributable: u64,
+    /// Growth of `acc_reward_per_share` during this deposit, including stream rewards
+    /// released since the last update. A streamed deposit shows up in `reward_rate` instead.
+    pub acc_reward_per_share_delta: u128,
+    /// Stream rate, scaled by `REWARD_MULTIPLIER`.
+    pub reward_rate: u128,
+}
+
+/// Multipliers are in percent; `rebate` is the bonus percent added on top of 100.
+#[event]
+pub struct RewardsClaimed {
+    pub user_stake: Pubkey,
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub base_reward: u64,
+    pub time_multiplier: u64,
     pub utilization_multiplier: u64,
-    pub last_fee_deposit_time: i64,
-    pub pool_info: [PoolInfo; 3],
-    pub insurance_fund: u64,
+    pub rebate: u64,
+    /// Reward after multipliers, capped by what the bonus budget can fund.
+    pub total_reward: u64,
+    pub payout: u64,
+    /// Unpaid remainder carried as an IOU after this claim.
+    pub owed_rewards: u64,
 }
 
-#[account]
-pub struct UserStake {
+#[event]
+pub struct Compounded {
+    pub user_stake: Pubkey,
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub amount: u64,
     pub staked_amount: u64,
-    pub reward_debt: u64,
-    pub pending_rewards: u64,
-    pub stake_timestamp: i64,
-    pub last_withdrawal_time: i64,
-    pub pool_type: u8,
-    pub trade_volume_7d: u64,
+    pub total_staked: u64,
 }
 
-#[account]
-pub struct LPUserStake {
+#[event]
+pub struct LpStaked {
+    pub lp_user_stake: Pubkey,
+    pub user: Pubkey,
+    pub amount: u64,
     pub staked_amount: u64,
-    pub reward_debt: u64,
-    pub pending_rewards: u64,
-    pub stake_timestamp: i64,
-    pub last_withdrawal_time: i64,
-    pub trade_volume_7d: u64,
+    pub warming_amount: u64,
+    pub lp_total_staked: u64,
+}
+
+#[event]
+pub struct LpUnstaked {
+    pub lp_user_stake: Pubkey,
+    pub amount: u64,
+    pub staked_amount: u64,
+    pub lp_total_staked: u64,
+}
+
+#[event]
+pub struct LpRewardsClaimed {
+    pub lp_user_stake: Pubkey,
+    pub base_reward: u64,
+    pub time_multiplier: u64,
+    pub lp_bonus_multiplier: u64,
+    pub utilization_multiplier: u64,
+    pub rebate: u64,
+    pub total_reward: u64,
+    pub payout: u64,
+    pub owed_rewards: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum AuthorityRole {
+    Owner,
+    Governance,
+}
+
+#[event]
+pub struct AuthorityTransferProposed {
+    pub role: AuthorityRole,
+    pub current: Pubkey,
+    pub pending: Pubkey,
+}
+
+#[event]
+pub struct AuthorityTransferred {
+    pub role: AuthorityRole,
+    pub previous: Pubkey,
+    pub current: Pubkey,
+}
+
+#[event]
+pub struct LockupExtended {
+    pub user_stake: Pubkey,
+    pub owner: Pubkey,
+    pub from_pool: Pubkey,
+    pub to_pool: Pubkey,
+    pub amount: u64,
+    pub reward_shares: u64,
+    pub lockup_end: i64,
+}
+
+#[event]
+pub struct TreasurySpent {
+    pub proposal: Pubkey,
+    pub recipient: Pubkey,
+    pub amount: u64,
+    pub memo: String,
+}
+
+#[event]
+pub struct DelegateChanged {
+    pub user_stake: Pubkey,
+    pub owner: Pubkey,
+    pub previous: Pubkey,
+    pub delegate: Pubkey,
+}
+
+#[event]
+pub struct PositionMigrated {
+    pub user_stake: Pubkey,
+    pub owner: Pubkey,
+    pub from_pool: Pubkey,
+    pub to_pool: Pubkey,
+    pub amount: u64,
+    pub pending_rewards: u64,
+}
+
+/// A staking pool. Each pool keeps its own totals and reward accumulator.
+#[account]
+pub struct Pool {
+    pub global_state: Pubkey,
+    pub pool_id: u64,
+    pub info: PoolInfo,
+    pub total_staked: u64,
+    pub total_reward_shares: u64,
+    pub acc_reward_per_share: u128,
+    /// `GlobalState.acc_reward_per_share` as of the last sync.
+    pub last_global_acc: u128,
+    /// Retired pools take no new stakes and charge no early-withdrawal penalty.
+    pub retired: bool,
+    pub bump: u8,
+    /// Gauge weight applied to this pool for the current epoch.
+    pub gauge_weight: u64,
+    /// `GlobalState.acc_reward_per_weight` as of the last sync.
+    pub last_acc_per_weight: u128,
+}
+
+impl Pool {
+    /// Bring the pool's accumulator up to date. Without gauge weights every pool earns the
+    /// same amount per reward share, so the pool takes on the global per-share growth;
+    /// with them, the pool's weighted cut of the emissions is spread over its own shares.
+    pub fn sync(&mut self, state: &mut GlobalState) -> Result<()> {
+        let delta = state.acc_reward_per_share.saturating_sub(self.last_global_acc);
+        self.acc_reward_per_share = self.acc_reward_per_share.checked_add(delta).ok_or(CustomError::MathOverflow)?;
+        self.last_global_acc = state.acc_reward_per_share;
+
+        let weight_delta = state.acc_reward_per_weight.saturating_sub(self.last_acc_per_weight);
+        self.last_acc_per_weight = state.acc_reward_per_weight;
+        let portion: u64 = (weight_delta * self.gauge_weight as u128 / GAUGE_PRECISION)
+            .try_into()
+            .map_err(|_| CustomError::MathOverflow)?;
+        if portion == 0 {
+            return Ok(());
+        }
+        if self.total_reward_shares == 0 {
+            // Nobody to pay; the pool's cut stays in the vault unallocated.
+            state.total_reward_liabilities = state.total_reward_liabilities.saturating_sub(portion);
+        } else {
+            let add_amount = portion as u128 * REWARD_MULTIPLIER as u128 / self.total_reward_shares as u128;
+            self.acc_reward_per_share = self.acc_reward_per_share.checked_add(add_amount).ok_or(CustomError::MathOverflow)?;
+        }
+        Ok(())
+    }
+
+    /// Cap on the early-withdrawal penalty for this pool; zero once the pool is retired.
+    pub fn max_penalty_bps(&self, state: &GlobalState) -> u64 {
+        if self.retired {
+            0
+        } else {
+            state.early_withdrawal_penalty
+        }
+    }
+}
+
+#[account]
+pub struct GlobalState {
+    pub total_staked: u64,
+    pub acc_reward_per_share: u128,
+    pub token_mint: Pubkey,
+    pub owner: Pubkey,
+    pub governance: Pubkey,
+    pub cooldown_period: i64,
+    pub early_withdrawal_penalty: u64,
+    pub min_withdraw_interval: i64,
+    pub min_claim_delay: i64,
+    pub insurance_fee_percent: u64,
+    pub utilization_multiplier: u64,
+    pub last_fee_deposit_time: i64,
+    pub insurance_fund: u64,
+    pub utilization_mode: UtilizationMode,
+    pub utilization_window: i64,
+    pub utilization_window_start: i64,
+    pub window_fee_total: u64,
+    pub trailing_fee_total: u64,
+    pub target_fee_yield_bps: u64,
+    pub min_utilization_multiplier: u64,
+    pub max_utilization_multiplier: u64,
+    pub utilization_adjustment: i64,
+    pub lp_total_staked: u64,
+    /// Rewards allocated through `acc_reward_per_share` that have not been claimed yet.
+    pub total_reward_liabilities: u64,
+    /// Explicitly funded balance that pays for claim multipliers.
+    pub bonus_budget: u64,
+    /// Rewards earned but not paid because the reward vault was short.
+    pub total_owed: u64,
+    /// Portion of the reward vault set aside to pay `total_owed`.
+    pub owed_reserve: u64,
+    /// Seconds over which each fee deposit is released; 0 allocates deposits instantly.
+    pub reward_duration: i64,
+    /// Tokens released per second by the running stream, scaled by `REWARD_MULTIPLIER`
+    /// so deposits smaller than `reward_duration` still stream.
+    pub reward_rate: u128,
+    pub period_finish: i64,
+    pub last_update_time: i64,
+    /// Seconds new stake spends in the warm-up balance before it earns rewards.
+    pub warmup_period: i64,
+    /// Treasury vault created by `initialize_treasury`; receives the protocol fee cut and
+    /// penalties routed to `PenaltyDestination::Treasury`. Fixed once set.
+    pub treasury: Pubkey,
+    /// Sum of every position's `reward_shares`.
+    pub total_reward_shares: u64,
+    /// Number of pools created; also the id of the next pool.
+    pub pool_count: u64,
+    /// Loyalty multiplier breakpoints, ordered by `min_duration`.
+    pub time_multiplier_schedule: Vec<MultiplierBreakpoint>,
+    /// Interpolate linearly between breakpoints instead of stepping.
+    pub interpolate_time_multiplier: bool,
+    /// Longest vote-escrow lock; a lock this long gets the full voting power and boost.
+    pub ve_max_lock: i64,
+    /// Reward boost (in percent) on fully locked stake, decaying with the remaining lock.
+    pub ve_max_boost: u64,
+    /// Per-share accumulator for LP stake.
+    pub lp_acc_reward_per_share: u128,
+    /// Rewards per unit of gauge weight, scaled by `GAUGE_PRECISION`. Pools draw their
+    /// share from it lazily in `Pool::sync`.
+    pub acc_reward_per_weight: u128,
+    /// Sum of the weights applied to every gauge this epoch. While zero, every pool and
+    /// LP stake share one per-share accumulator.
+    pub total_gauge_weight: u64,
+    /// Weight currently applied to the LP gauge.
+    pub lp_gauge_weight: u64,
+    /// `StakeHistory` page the next global checkpoint is written to.
+    pub stake_history_page: u64,
+    /// Latest global checkpoint, so unchanged totals are not written again.
+    pub last_stake_checkpoint: GlobalCheckpoint,
+    pub proposal_config: ProposalConfig,
+    /// Protocol cut of every fee deposit, in basis points, sent to `treasury`.
+    pub treasury_fee_bps: u64,
+    /// Owner key nominated by `propose_owner_transfer`; default when none is pending.
+    pub pending_owner: Pubkey,
+    /// Governance key nominated by `propose_governance_transfer`; default when none is pending.
+    pub pending_governance: Pubkey,
+    /// Soft bounds on how far `update_parameters` may move each field at once.
+    pub parameter_limits: ParameterLimits,
+    /// Looser limits queued by `set_parameter_limits`, installed at `parameter_limits_effective_at`.
+    pub pending_parameter_limits: Option<ParameterLimits>,
+    pub parameter_limits_effective_at: i64,
+    /// Escrow for proposal deposits, created by `initialize_proposal_deposits`.
+    pub proposal_deposit_vault: Pubkey,
+    /// Token account holding fees, bonus budget and insurance; fixed at `initialize`.
+    pub reward_vault: Pubkey,
+    /// Streamed rewards not yet released because they are below one token, scaled by
+    /// `REWARD_MULTIPLIER`.
+    pub reward_remainder: u128,
+    /// Token account holding staked principal; fixed at `initialize`.
+    pub staking_vault: Pubkey,
+    /// Number of gauges created.
+    pub gauge_count: u64,
+    /// Epoch the gauge weights were last applied for.
+    pub gauge_epoch: u64,
+}
+
+#[account]
+pub struct UserStake {
+    pub staked_amount: u64,
+    pub reward_debt: u64,
+    pub pending_rewards: u64,
+    pub stake_timestamp: i64,
+    pub last_withdrawal_time: i64,
+    pub pool: Pubkey,
+    pub trade_volume_7d: u64,
+    pub owed_rewards: u64,
+    /// Stake deposited but not yet earning; promoted on the first touch (or
+    /// `poke_position`) after `warmup_end`.
+    pub warming_amount: u64,
+    /// Amount-weighted end of the warm-up across the pending deposits.
+    pub warmup_end: i64,
+    /// Deposit lots, oldest first. At most `MAX_STAKE_LOTS` entries.
+    pub lots: Vec<StakeLot>,
+    pub owner: Pubkey,
+    /// `staked_amount` weighted by the pool's `apr_multiplier`; rewards accrue per share.
+    pub reward_shares: u64,
+    /// Portion of `staked_amount` locked in vote escrow until `ve_lock_end`.
+    pub ve_amount: u64,
+    pub ve_lock_end: i64,
+    /// Voting power lost per second, scaled by `VE_PRECISION`.
+    pub ve_slope: u128,
+    /// Key allowed to cast this position's proposal votes; the owner when unset.
+    pub delegate: Pubkey,
+    /// Balance, delegate and lock history, oldest first. At most
+    /// `MAX_POSITION_CHECKPOINTS` entries.
+    pub checkpoints: Vec<PositionCheckpoint>,
+    /// Epoch of the position's latest gauge votes and the share of its power (in basis
+    /// points) already cast in it.
+    pub gauge_vote_epoch: u64,
+    pub gauge_vote_bps: u64,
+}
+
+impl UserStake {
+    /// Harvest what the current shares accrued into `pending_rewards` and reset the debt.
+    pub fn settle_rewards(&mut self, acc_reward_per_share: u128) -> Result<()> {
+        let accrued = accrued_rewards(self.reward_shares, acc_reward_per_share)?;
+        let pending = accrued.checked_sub(self.reward_debt).ok_or(CustomError::MathOverflow)?;
+        self.pending_rewards = self.pending_rewards.checked_add(pending).ok_or(CustomError::MathOverflow)?;
+        self.reward_debt = accrued;
+        Ok(())
+    }
+
+    /// Re-derive `reward_shares` from the earning balance, the pool's current weight and
+    /// the position's vote-escrow boost, keeping the pool and global share totals in step.
+    /// Call after `settle_rewards` whenever the balance or pool changes; the debt is reset
+    /// against the new share count and the position checkpoint is written; the caller
+    /// records the global totals in its `StakeHistory` page.
+    /// The boost decays with the lock, so it is re-read on every touch; `poke_position`
+    /// lets anyone apply the decay to a position its owner leaves alone.
+    pub fn update_shares(&mut self, state: &mut GlobalState, pool: &mut Pool, now: i64) -> Result<()> {
+        let boosted = self.voting_power_at(now).min(self.staked_amount) as u128 * state.ve_max_boost as u128 / 100;
+        let shares: u64 = ((self.staked_amount as u128 + boosted) * (pool.info.apr_multiplier as u128) / 100)
+            .try_into()
+            .map_err(|_| CustomError::MathOverflow)?;
+        state.total_reward_shares = state.total_reward_shares
+            .checked_sub(self.reward_shares)
+            .and_then(|total| total.checked_add(shares))
+            .ok_or(CustomError::MathOverflow)?;
+        pool.total_reward_shares = pool.total_reward_shares
+            .checked_sub(self.reward_shares)
+            .and_then(|total| total.checked_add(shares))
+            .ok_or(CustomError::MathOverflow)?;
+        self.reward_shares = shares;
+        self.reward_debt = accrued_rewards(shares, pool.acc_reward_per_share)?;
+        self.write_checkpoint(now);
+        Ok(())
+    }
+
+    /// Vote-escrow voting power at `t`, decaying linearly to zero at `ve_lock_end`.
+    pub fn voting_power_at(&self, t: i64) -> u64 {
+        ve_power(self.ve_slope, self.ve_lock_end, t)
+    }
+
+    /// Record the current balance, delegate and lock as of `now`, replacing a checkpoint
+    /// written earlier in the same second. Nothing is written if nothing changed, so
+    /// claims do not push older history out.
+    pub fn write_checkpoint(&mut self, now: i64) {
+        let checkpoint = PositionCheckpoint {
+            timestamp: now,
+            staked_amount: self.staked_amount,
+            warming_amount: self.warming_amount,
+            delegate: self.delegate,
+            ve_slope: self.ve_slope,
+            ve_lock_end: self.ve_lock_end,
+        };
+        let unchanged = self.checkpoints
+            .last()
+            .is_some_and(|last| *last == PositionCheckpoint { timestamp: last.timestamp, ..checkpoint });
+        if unchanged {
+            return;
+        }
+        if self.checkpoints.last().is_some_and(|last| last.timestamp == now) {
+            self.checkpoints.pop();
+        } else if self.checkpoints.len() >= MAX_POSITION_CHECKPOINTS {
+            // A checkpoint that only changed balances can go without affecting votes: the
+            // one before it carries the same lock and delegate.
+            let dropped = (1..self.checkpoints.len())
+                .find(|&i| self.checkpoints[i].same_votes(&self.checkpoints[i - 1]))
+                .unwrap_or(0);
+            self.checkpoints.remove(dropped);
+        }
+        self.checkpoints.push(checkpoint);
+    }
+
+    /// Latest checkpoint at or before `t`, if the history reaches back that far.
+    pub fn checkpoint_at(&self, t: i64) -> Option<&PositionCheckpoint> {
+        self.checkpoints.iter().rev().find(|checkpoint| checkpoint.timestamp <= t)
+    }
+
+    /// Take the position's stake and shares out of `pool`. Rewards must be settled first.
+    pub fn leave_pool(&mut self, state: &mut GlobalState, pool: &mut Pool, now: i64) -> Result<()> {
+        pool.total_staked = pool.total_staked.checked_sub(self.staked_amount).ok_or(CustomError::MathOverflow)?;
+        let staked_amount = self.staked_amount;
+        self.staked_amount = 0;
+        self.update_shares(state, pool, now)?;
+        self.staked_amount = staked_amount;
+        Ok(())
+    }
+
+    /// Put the position's stake and shares into `pool`.
+    pub fn join_pool(&mut self, state: &mut GlobalState, pool: &mut Pool, now: i64) -> Result<()> {
+        pool.total_staked = pool.total_staked.checked_add(self.staked_amount).ok_or(CustomError::MathOverflow)?;
+        self.update_shares(state, pool, now)
+    }
+
+    /// Move a matured warm-up balance into the earning stake, settling what the existing
+    /// shares accrued first and updating the pool and global totals.
+    ///
+    /// The balance is not part of any share total until this runs, so it earns from the
+    /// promotion, not from `warmup_end`; rewards allocated in between went to the stake
+    /// that was earning. Every touch of the position promotes, and `poke_position` lets
+    /// anyone promote right at `warmup_end`.
+    pub fn promote_warming(&mut self, state: &mut GlobalState, pool: &mut Pool, now: i64) -> Result<()> {
+        if self.warming_amount == 0 || now < self.warmup_end {
+            return Ok(());
+        }
+        self.settle_rewards(pool.acc_reward_per_share)?;
+        let promoted = self.warming_amount;
+        self.staked_amount = self.staked_amount.checked_add(promoted).ok_or(CustomError::MathOverflow)?;
+        self.warming_amount = 0;
+        state.total_staked = state.total_staked.checked_add(promoted).ok_or(CustomError::MathOverflow)?;
+        pool.total_staked = pool.total_staked.checked_add(promoted).ok_or(CustomError::MathOverflow)?;
+        self.update_shares(state, pool, now)
+    }
+
+    /// Record a deposit lot. When the list is full the newest two lots locked in the same
+    /// pool are merged under the later timestamp, so only recent deposits have their lockup
+    /// extended and older principal keeps its own deposit time. Lots never change pool, so
+    /// migrated stake stays penalty-free.
+    pub fn push_lot(&mut self, lot: StakeLot) -> Result<()> {
+        self.lots.push(lot);
+        if self.lots.len() > MAX_STAKE_LOTS {
+            let newer = (1..self.lots.len())
+                .rev()
+                .find(|&i| self.lots[i - 1].pool == self.lots[i].pool)
+                .ok_or(CustomError::TooManyStakeLots)?;
+            let merged = self.lots.remove(newer);
+            let older = &mut self.lots[newer - 1];
+            older.amount = older.amount.checked_add(merged.amount).ok_or(CustomError::MathOverflow)?;
+            older.timestamp = merged.timestamp;
+        }
+        Ok(())
+    }
+
+    /// Remove `amount` from the lots in FIFO order and return the early-withdrawal penalty
+    /// owed on it, each lot priced on the penalty curve of `pool` from its own deposit time.
+    /// Lots from another pool were migrated out of a retired one and carry no penalty.
+    /// Balance not covered by lots (positions opened before lots were tracked) falls back
+    /// to the position's `stake_timestamp`.
+    pub fn consume_lots(
+        &mut self,
+        amount: u64,
+        now: i64,
+        pool: Pubkey,
+        pool_info: &PoolInfo,
+        max_penalty_bps: u64,
+    ) -> Result<u64> {
+        let mut remaining = amount;
+        let mut penalty = 0u64;
+        while remaining > 0 && !self.lots.is_empty() {
+            let lot = &mut self.lots[0];
+            let taken = remaining.min(lot.amount);
+            let bps = if lot.pool == pool {
+                pool_info.penalty_bps(lot.timestamp, now, max_penalty_bps)
+            } else {
+                0
+            };
+            penalty = penalty
+                .checked_add(taken.checked_mul(bps).ok_or(CustomError::MathOverflow)? / 10_000)
+                .ok_or(CustomError::MathOverflow)?;
+            lot.amount -= taken;
+            remaining -= taken;
+            if lot.amount == 0 {
+                self.lots.remove(0);
+            }
+        }
+        if remaining > 0 {
+            let bps = pool_info.penalty_bps(self.stake_timestamp, now, max_penalty_bps);
+            penalty = penalty
+                .checked_add(remaining.checked_mul(bps).ok_or(CustomError::MathOverflow)? / 10_000)
+                .ok_or(CustomError::MathOverflow)?;
+        }
+        Ok(penalty)
+    }
+
+    /// Penalty `consume_lots` would charge for `amount` without modifying the position.
+    pub fn quote_penalty(
+        &self,
+        amount: u64,
+        now: i64,
+        pool: Pubkey,
+        pool_info: &PoolInfo,
+        max_penalty_bps: u64,
+    ) -> Result<u64> {
+        self.clone().consume_lots(amount, now, pool, pool_info, max_penalty_bps)
+    }
+}
+
+#[account]
+pub struct LPUserStake {
+    pub staked_amount: u64,
+    pub reward_debt: u64,
+    pub pending_rewards: u64,
+    pub stake_timestamp: i64,
+    pub last_withdrawal_time: i64,
+    pub trade_volume_7d: u64,
+    pub owed_rewards: u64,
+    pub warming_amount: u64,
+    pub warmup_end: i64,
+}
+
+impl LPUserStake {
+    /// Move a matured warm-up balance into the earning stake, settling what the existing
+    /// stake accrued first. Returns the promoted amount so the caller can update totals.
+    /// As with `UserStake::promote_warming`, the balance earns from the promotion; see
+    /// `poke_lp_position`.
+    pub fn promote_warming(&mut self, acc_reward_per_share: u128, now: i64) -> Result<u64> {
+        if self.warming_amount == 0 || now < self.warmup_end {
+            return Ok(0);
+        }
+        let pending = accrued_rewards(self.staked_amount, acc_reward_per_share)?
+            .checked_sub(self.reward_debt)
+            .ok_or(CustomError::MathOverflow)?;
+        self.pending_rewards = self.pending_rewards.checked_add(pending).ok_or(CustomError::MathOverflow)?;
+        let promoted = self.warming_amount;
+        self.staked_amount = self.staked_amount.checked_add(promoted).ok_or(CustomError::MathOverflow)?;
+        self.warming_amount = 0;
+        self.reward_debt = accrued_rewards(self.staked_amount, acc_reward_per_share)?;
+        Ok(promoted)
+    }
+}
+
+#[account]
+pub struct Proposal {
+    pub proposal_id: u64,
+    pub proposer: Pubkey,
+    pub proposal_data: String,
+    pub snapshot_timestamp: i64,
+    /// Votes for the proposal.
+    pub vote_count: u64,
+    pub executed: bool,
+    /// Total vote-escrow power at `snapshot_timestamp`.
+    pub total_voting_power: u64,
+    pub global_state: Pubkey,
+    /// PYT escrowed by the proposer.
+    pub deposit: u64,
+    pub voting_start: i64,
+    pub voting_end: i64,
+    /// Votes for needed to pass, fixed from the snapshot's total voting power.
+    pub quorum: u64,
+    /// Below this many votes the deposit is slashed.
+    pub slash_quorum: u64,
+    /// After this the proposal can no longer be executed and may be closed.
+    pub expires_at: i64,
+    /// Treasury payment made once the proposal is executed.
+    pub spend: Option<SpendAction>,
+    pub spend_paid: bool,
+    /// Votes against the proposal; it passes only with more votes for than against.
+    pub against_count: u64,
+}
+
+/// A payment out of the treasury vault, approved by an executed proposal.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct SpendAction {
+    /// Token account receiving the payment.
+    pub recipient: Pubkey,
+    pub amount: u64,
+    pub memo: String,
+}
+
+/// Gauge directing a share of fee emissions to one pool, or to LP stake when `target`
+/// is the global state itself. Votes cast in one epoch are tallied for the next, and
+/// become the gauge's weight once `checkpoint_gauges` runs in that epoch.
+#[account]
+pub struct Gauge {
+    pub global_state: Pubkey,
+    pub target: Pubkey,
+    /// Epoch the `votes` tally applies to.
+    pub votes_epoch: u64,
+    pub votes: u64,
+    /// Tally displaced when voting for a later epoch began, kept until it is applied.
+    pub prior_votes_epoch: u64,
+    pub prior_votes: u64,
+    /// Epoch `weight` was applied for.
+    pub weight_epoch: u64,
+    pub weight: u64,
+    pub bump: u8,
+}
+
+impl Gauge {
+    /// Votes tallied for `epoch`, or zero if none were cast for it.
+    fn votes_for(&self, epoch: u64) -> u64 {
+        if self.votes_epoch == epoch {
+            self.votes
+        } else if self.prior_votes_epoch == epoch {
+            self.prior_votes
+        } else {
+            0
+        }
+    }
+}
+
+/// m-of-n signer set acting as governance through its signer PDA.
+#[account]
+pub struct Multisig {
+    pub global_state: Pubkey,
+    pub signers: Vec<Pubkey>,
+    pub threshold: u8,
+    /// Bumped whenever the signer set changes, invalidating pending actions.
+    pub owner_set_seqno: u64,
+    pub signer_bump: u8,
+    pub bump: u8,
+}
+
+impl Multisig {
+    fn signer_index(&self, key: &Pubkey) -> Result<usize> {
+        self.signers
+            .iter()
+            .position(|signer| signer == key)
+            .ok_or_else(|| CustomError::NotMultisigSigner.into())
+    }
+}
+
+/// An instruction on this program awaiting multisig approval.
+#[account]
+pub struct GovernanceAction {
+    pub multisig: Pubkey,
+    pub accounts: Vec<ActionAccount>,
+    pub data: Vec<u8>,
+    /// One flag per multisig signer, in signer order.
+    pub approvals: Vec<bool>,
+    pub owner_set_seqno: u64,
+    pub executed: bool,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ActionAccount {
+    pub pubkey: Pubkey,
+    pub is_signer: bool,
+    pub is_writable: bool,
+}
+
+/// Marks that a position has voted on a proposal.
+#[account]
+pub struct VoteRecord {
+    pub proposal: Pubkey,
+    pub user_stake: Pubkey,
+    /// Owner or delegate that cast the vote.
+    pub voter: Pubkey,
+    pub weight: u64,
+    /// Whether the vote was for the proposal.
+    pub support: bool,
+}
+
+/// Breakdown of what a claim would pay, returned by `quote_rewards` and `quote_lp_rewards`.
+/// Multipliers are in percent; `rebate` is the bonus percent added on top of 100.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct RewardQuote {
+    /// Rewards allocated to the position before any multiplier.
+    pub base_reward: u64,
+    pub time_multiplier: u64,
+    /// 100 for PYT stake.
+    pub lp_bonus_multiplier: u64,
+    pub utilization_multiplier: u64,
+    pub rebate: u64,
+    /// Reward after multipliers, capped by what the bonus budget can fund.
+    pub total_reward: u64,
+    /// Amount the claim would transfer, including repaid IOUs.
+    pub payout: u64,
+    /// IOU left on the position after the claim.
+    pub owed_rewards: u64,
+    /// Earliest time the cooldown and claim-delay gates both allow a claim.
+    pub claimable_at: i64,
+}
+
+/// The values set by `initialize` and `update_parameters`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct ProtocolParameters {
+    pub cooldown_period: i64,
+    pub early_withdrawal_penalty: u64,
+    pub min_withdraw_interval: i64,
+    pub min_claim_delay: i64,
+    pub insurance_fee_percent: u64,
+    pub utilization_multiplier: u64,
+}
+
+impl ProtocolParameters {
+    fn current(state: &GlobalState) -> Self {
+        Self {
+            cooldown_period: state.cooldown_period,
+            early_withdrawal_penalty: state.early_withdrawal_penalty,
+            min_withdraw_interval: state.min_withdraw_interval,
+            min_claim_delay: state.min_claim_delay,
+            insurance_fee_percent: state.insurance_fee_percent,
+            utilization_multiplier: state.utilization_multiplier,
+        }
+    }
+
+    /// Hard bounds that hold regardless of governance settings.
+    fn validate(&self, treasury_fee_bps: u64) -> Result<()> {
+        check_parameter(self.cooldown_period >= 0, "cooldown_period")?;
+        check_parameter(self.early_withdrawal_penalty <= 10_000, "early_withdrawal_penalty")?;
+        check_parameter(self.min_withdraw_interval >= 0, "min_withdraw_interval")?;
+        check_parameter(self.min_claim_delay >= 0, "min_claim_delay")?;
+        check_parameter(
+            self.insurance_fee_percent
+                .checked_add(treasury_fee_bps)
+                .is_some_and(|total| total <= 10_000),
+            "insurance_fee_percent",
+        )?;
+        check_parameter(
+            self.utilization_multiplier > 0 && self.utilization_multiplier <= MAX_DERIVED_UTILIZATION,
+            "utilization_multiplier",
+        )?;
+        Ok(())
+    }
+}
+
+/// Largest change a single `update_parameters` call may make to each field; 0 leaves the
+/// field unrestricted.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct ParameterLimits {
+    pub max_cooldown_change: u64,
+    pub max_penalty_change: u64,
+    pub max_withdraw_interval_change: u64,
+    pub max_claim_delay_change: u64,
+    pub max_insurance_fee_change: u64,
+    pub max_utilization_change: u64,
+}
+
+impl ParameterLimits {
+    /// Whether any field allows a larger change than in `current` (0 being unlimited).
+    fn loosens(&self, current: &ParameterLimits) -> bool {
+        let looser = |new: u64, old: u64| old != 0 && (new == 0 || new > old);
+        looser(self.max_cooldown_change, current.max_cooldown_change)
+            || looser(self.max_penalty_change, current.max_penalty_change)
+            || looser(self.max_withdraw_interval_change, current.max_withdraw_interval_change)
+            || looser(self.max_claim_delay_change, current.max_claim_delay_change)
+            || looser(self.max_insurance_fee_change, current.max_insurance_fee_change)
+            || looser(self.max_utilization_change, current.max_utilization_change)
+    }
+
+    fn check(&self, old: &ProtocolParameters, new: &ProtocolParameters) -> Result<()> {
+        check_change(
+            "cooldown_period",
+            old.cooldown_period as i128,
+            new.cooldown_period as i128,
+            self.max_cooldown_change,
+        )?;
+        check_change(
+            "early_withdrawal_penalty",
+            old.early_withdrawal_penalty as i128,
+            new.early_withdrawal_penalty as i128,
+            self.max_penalty_change,
+        )?;
+        check_change(
+            "min_withdraw_interval",
+            old.min_withdraw_interval as i128,
+            new.min_withdraw_interval as i128,
+            self.max_withdraw_interval_change,
+        )?;
+        check_change(
+            "min_claim_delay",
+            old.min_claim_delay as i128,
+            new.min_claim_delay as i128,
+            self.max_claim_delay_change,
+        )?;
+        check_change(
+            "insurance_fee_percent",
+            old.insurance_fee_percent as i128,
+            new.insurance_fee_percent as i128,
+            self.max_insurance_fee_change,
+        )?;
+        check_change(
+            "utilization_multiplier",
+            old.utilization_multiplier as i128,
+            new.utilization_multiplier as i128,
+            self.max_utilization_change,
+        )?;
+        Ok(())
+    }
+}
+
+/// Governance settings for proposal submission and voting.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct ProposalConfig {
+    /// PYT a proposer escrows on submission; refunded unless the proposal is slashed.
+    pub deposit: u64,
+    /// Seconds between submission and the start of voting; the proposer may cancel meanwhile.
+    pub voting_delay: i64,
+    pub voting_period: i64,
+    /// Seconds after voting ends during which a proposal can still be executed.
+    pub execution_window: i64,
+    /// Share of the snapshot's total voting power (in basis points) needed to pass.
+    pub quorum_bps: u64,
+    /// Proposals drawing less than this share of voting power forfeit their deposit.
+    pub slash_quorum_bps: u64,
+}
+
+impl ProposalConfig {
+    fn validate(&self) -> Result<()> {
+        require!(
+            self.voting_delay >= 0 && self.voting_period > 0 && self.execution_window >= 0,
+            CustomError::InvalidProposalConfig
+        );
+        require!(
+            self.quorum_bps <= 10_000 && self.slash_quorum_bps <= self.quorum_bps,
+            CustomError::InvalidProposalConfig
+        );
+        Ok(())
+    }
+}
+
+/// Global stake totals as of `timestamp`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GlobalCheckpoint {
+    pub timestamp: i64,
+    pub total_staked: u64,
+    pub lp_total_staked: u64,
+}
+
+/// A position's balances, delegate and vote-escrow lock as of `timestamp`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PositionCheckpoint {
+    pub timestamp: i64,
+    pub staked_amount: u64,
+    pub warming_amount: u64,
+    pub delegate: Pubkey,
+    pub ve_slope: u128,
+    pub ve_lock_end: i64,
+}
+
+impl PositionCheckpoint {
+    /// Voting power this lock carried at `t`.
+    pub fn voting_power_at(&self, t: i64) -> u64 {
+        ve_power(self.ve_slope, self.ve_lock_end, t)
+    }
+
+    /// Whether both checkpoints give the same votes to the same holder.
+    fn same_votes(&self, other: &PositionCheckpoint) -> bool {
+        self.delegate == other.delegate && self.ve_slope == other.ve_slope && self.ve_lock_end == other.ve_lock_end
+    }
+}
+
+/// Append-only page of global stake checkpoints, oldest first. Pages are opened in
+/// order with `open_stake_history_page`; once `GlobalState.stake_history_page` is full
+/// writes move on to the next one.
+#[account]
+pub struct StakeHistory {
+    pub global_state: Pubkey,
+    pub page: u64,
+    pub checkpoints: Vec<GlobalCheckpoint>,
+    pub bump: u8,
+}
+
+impl StakeHistory {
+    /// Record `total_staked` and `lp_total_staked` as of `now`, replacing a checkpoint
+    /// written earlier in the same second. Nothing is written if neither total changed.
+    pub fn record(&mut self, state: &mut GlobalState, now: i64) -> Result<()> {
+        let checkpoint = GlobalCheckpoint {
+            timestamp: now,
+            total_staked: state.total_staked,
+            lp_total_staked: state.lp_total_staked,
+        };
+        let last = state.last_stake_checkpoint;
+        if last == (GlobalCheckpoint { timestamp: last.timestamp, ..checkpoint }) {
+            return Ok(());
+        }
+        if self.checkpoints.last().is_some_and(|last| last.timestamp == now) {
+            self.checkpoints.pop();
+        } else {
+            require!(self.checkpoints.len() < STAKE_HISTORY_PAGE_LEN, CustomError::StakeHistoryPageFull);
+        }
+        state.last_stake_checkpoint = checkpoint;
+        self.checkpoints.push(checkpoint);
+        if self.checkpoints.len() == STAKE_HISTORY_PAGE_LEN && state.stake_history_page == self.page {
+            state.stake_history_page = self.page.checked_add(1).ok_or(CustomError::MathOverflow)?;
+        }
+        Ok(())
+    }
+
+    /// Latest checkpoint in this page at or before `t`.
+    pub fn checkpoint_at(&self, t: i64) -> Option<&GlobalCheckpoint> {
+        self.checkpoints.iter().rev().find(|checkpoint| checkpoint.timestamp <= t)
+    }
+}
+
+/// Global vote-escrow curve. Total voting power is `bias / VE_PRECISION`, falling by
+/// `slope` each second; as locks expire their slope is removed at the week boundary.
+#[account]
+pub struct VeState {
+    pub global_state: Pubkey,
+    pub bias: u128,
+    pub slope: u128,
+    pub last_checkpoint: i64,
+    /// Slope expiring at each week boundary, indexed by week number modulo `VE_SCHEDULE_WEEKS`.
+    pub slope_changes: Vec<u128>,
+    pub bump: u8,
+}
+
+impl VeState {
+    fn slot(week_start: i64) -> usize {
+        (week_start / WEEK) as usize % VE_SCHEDULE_WEEKS
+    }
+
+    /// Advance the curve to `now`, week by week, dropping the slope of locks that ended.
+    pub fn checkpoint(&mut self, now: i64) -> Result<()> {
+        let mut t = self.last_checkpoint;
+        while t < now {
+            let week_end = (t / WEEK + 1) * WEEK;
+            let t_next = week_end.min(now);
+            self.bias = self.bias.saturating_sub(self.slope * (t_next - t) as u128);
+            if t_next == week_end {
+                let slot = Self::slot(week_end);
+                self.slope = self.slope.saturating_sub(self.slope_changes[slot]);
+                self.slope_changes[slot] = 0;
+            }
+            t = t_next;
+        }
+        self.last_checkpoint = self.last_checkpoint.max(now);
+        Ok(())
+    }
+
+    /// Total voting power as of the last checkpoint.
+    pub fn total_power(&self) -> u64 {
+        (self.bias / VE_PRECISION) as u64
+    }
+
+    /// Total voting power at `t`, projected forward from the last checkpoint.
+    pub fn total_power_at(&self, t: i64) -> Result<u64> {
+        require!(t >= self.last_checkpoint, CustomError::InvalidVeLock);
+        let mut projected = self.clone();
+        projected.checkpoint(t)?;
+        Ok(projected.total_power())
+    }
+
+    /// Swap a position's lock on the curve. The curve must be checkpointed to `now` and
+    /// `old_end` (if the old slope is non-zero) must still be in the future.
+    fn replace_lock(&mut self, old_slope: u128, old_end: i64, new_slope: u128, new_end: i64, now: i64) -> Result<()> {
+        if old_slope > 0 {
+            self.slope = self.slope.saturating_sub(old_slope);
+            self.bias = self.bias.saturating_sub(old_slope * (old_end - now) as u128);
+            let slot = Self::slot(old_end);
+            self.slope_changes[slot] = self.slope_changes[slot].saturating_sub(old_slope);
+        }
+        let new_bias = new_slope.checked_mul((new_end - now) as u128).ok_or(CustomError::MathOverflow)?;
+        self.slope = self.slope.checked_add(new_slope).ok_or(CustomError::MathOverflow)?;
+        self.bias = self.bias.checked_add(new_bias).ok_or(CustomError::MathOverflow)?;
+        let slot = Self::slot(new_end);
+        self.slope_changes[slot] = self.slope_changes[slot].checked_add(new_slope).ok_or(CustomError::MathOverflow)?;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct Initialize<'info> {
+    #[account(init, payer = owner, space = 1600)]
+    pub global_state: Account<'info, GlobalState>,
+    pub token_mint: Account<'info, Mint>,
+    /// CHECK: PDA authority.
+    #[account(seeds = [b"vault"], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    #[account(token::mint = token_mint, token::authority = vault_authority)]
+    pub reward_vault: Account<'info, TokenAccount>,
+    /// Kept apart from `reward_vault` so staked principal never counts as distributable.
+    #[account(
+        token::mint = token_mint,
+        token::authority = vault_authority,
+        constraint = staking_vault.key() != reward_vault.key()
+    )]
+    pub staking_vault: Account<'info, TokenAccount>,
+    /// Created here so claims work before governance ever touches the tiers.
+    #[account(
+        init,
+        payer = owner,
+        space = 200,
+        seeds = [b"rebate_config", global_state.key().as_ref()],
+        bump
+    )]
+    pub rebate_config: Account<'info, RebateConfig>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateParameters<'info> {
+    #[account(mut, has_one = governance)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub governance: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct OwnerOnly<'info> {
+    #[account(mut, has_one = owner)]
+    pub global_state: Account<'info, GlobalState>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptOwnerTransfer<'info> {
+    #[account(mut)]
+    pub global_state: Account<'info, GlobalState>,
+    pub new_owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptGovernanceTransfer<'info> {
+    #[account(mut)]
+    pub global_state: Account<'info, GlobalState>,
+    pub new_governance: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CreateMultisig<'info> {
+    #[account(mut, has_one = governance)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(
+        init,
+        payer = governance,
+        space = 400,
+        seeds = [b"multisig", global_state.key().as_ref()],
+        bump
+    )]
+    pub multisig: Account<'info, Multisig>,
+    /// CHECK: PDA that signs governance instructions for the multisig.
+    #[account(seeds = [b"multisig_signer", multisig.key().as_ref()], bump)]
+    pub multisig_signer: AccountInfo<'info>,
+    #[account(mut)]
+    pub governance: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MultisigAuth<'info> {
+    #[account(mut)]
+    pub multisig: Account<'info, Multisig>,
+    #[account(seeds = [b"multisig_signer", multisig.key().as_ref()], bump = multisig.signer_bump)]
+    pub multisig_signer: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ProposeGovernanceAction<'info> {
+    pub multisig: Account<'info, Multisig>,
+    #[account(init, payer = proposer, space = 1000)]
+    pub action: Account<'info, GovernanceAction>,
+    #[account(mut)]
+    pub proposer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ApproveGovernanceAction<'info> {
+    pub multisig: Account<'info, Multisig>,
+    #[account(mut, has_one = multisig)]
+    pub action: Account<'info, GovernanceAction>,
+    pub signer: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteGovernanceAction<'info> {
+    /// Not `mut`: the action may rewrite the multisig itself.
+    pub multisig: Account<'info, Multisig>,
+    /// CHECK: PDA that signs the action.
+    #[account(seeds = [b"multisig_signer", multisig.key().as_ref()], bump = multisig.signer_bump)]
+    pub multisig_signer: AccountInfo<'info>,
+    #[account(mut, has_one = multisig)]
+    pub action: Account<'info, GovernanceAction>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeTreasury<'info> {
+    #[account(mut, has_one = governance, has_one = token_mint)]
+    pub global_state: Account<'info, GlobalState>,
+    pub token_mint: Account<'info, Mint>,
+    /// CHECK: PDA authority.
+    #[account(seeds = [b"treasury", global_state.key().as_ref()], bump)]
+    pub treasury_authority: AccountInfo<'info>,
+    #[account(
+        init,
+        payer = governance,
+        seeds = [b"treasury_vault", global_state.key().as_ref()],
+        bump,
+        token::mint = token_mint,
+        token::authority = treasury_authority
+    )]
+    pub treasury_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub governance: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeProposalDeposits<'info> {
+    #[account(mut, has_one = governance, has_one = token_mint)]
+    pub global_state: Account<'info, GlobalState>,
+    pub token_mint: Account<'info, Mint>,
+    /// CHECK: PDA authority.
+    #[account(seeds = [b"proposal_deposits", global_state.key().as_ref()], bump)]
+    pub deposit_authority: AccountInfo<'info>,
+    #[account(
+        init,
+        payer = governance,
+        seeds = [b"proposal_deposit_vault", global_state.key().as_ref()],
+        bump,
+        token::mint = token_mint,
+        token::authority = deposit_authority
+    )]
+    pub deposit_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub governance: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeRebateConfig<'info> {
+    #[account(has_one = governance)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(
+        init,
+        payer = governance,
+        space = 200,
+        seeds = [b"rebate_config", global_state.key().as_ref()],
+        bump
+    )]
+    pub rebate_config: Account<'info, RebateConfig>,
+    #[account(mut)]
+    pub governance: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeVe<'info> {
+    #[account(has_one = governance)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(
+        init,
+        payer = governance,
+        space = 3500,
+        seeds = [b"ve_state", global_state.key().as_ref()],
+        bump
+    )]
+    pub ve_state: Account<'info, VeState>,
+    #[account(mut)]
+    pub governance: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetRebateTiers<'info> {
+    #[account(has_one = governance)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut, has_one = global_state)]
+    pub rebate_config: Account<'info, RebateConfig>,
+    pub governance: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CreatePool<'info> {
+    #[account(mut, has_one = governance)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(
+        init,
+        payer = governance,
+        space = 200,
+        seeds = [b"pool", global_state.key().as_ref(), &global_state.pool_count.to_le_bytes()],
+        bump
+    )]
+    pub pool: Account<'info, Pool>,
+    #[account(mut)]
+    pub governance: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdatePool<'info> {
+    #[account(has_one = governance)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut, has_one = global_state)]
+    pub pool: Account<'info, Pool>,
+    pub governance: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct Stake<'info> {
+    #[account(mut)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(
+        mut,
+        seeds = [b"stake_history", global_state.key().as_ref(), &global_state.stake_history_page.to_le_bytes()],
+        bump = stake_history.bump
+    )]
+    pub stake_history: Account<'info, StakeHistory>,
+    #[account(mut, has_one = global_state)]
+    pub pool: Account<'info, Pool>,
+    /// Only the owner may top up a position; the first deposit claims an unowned one.
+    #[account(mut, constraint = user_stake.owner == Pubkey::default() || user_stake.owner == user.key())]
+    pub user_stake: Account<'info, UserStake>,
+    #[account(mut)]
+    pub user_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = global_state.staking_vault)]
+    pub staking_vault: Account<'info, TokenAccount>,
+    pub user: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct Unstake<'info> {
+    #[account(mut)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(
+        mut,
+        seeds = [b"stake_history", global_state.key().as_ref(), &global_state.stake_history_page.to_le_bytes()],
+        bump = stake_history.bump
+    )]
+    pub stake_history: Account<'info, StakeHistory>,
+    #[account(mut, has_one = global_state, address = user_stake.pool)]
+    pub pool: Account<'info, Pool>,
+    #[account(mut)]
+    pub user_stake: Account<'info, UserStake>,
+    #[account(mut, address = global_state.staking_vault)]
+    pub staking_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_token_account: Account<'info, TokenAccount>,
+    /// CHECK: PDA authority.
+    #[account(seeds = [b"vault"], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    #[account(mut, address = global_state.reward_vault)]
+    pub reward_vault: Account<'info, TokenAccount>,
+    /// Required only when a pool routes penalties to the treasury.
+    #[account(mut, address = global_state.treasury)]
+    pub treasury: Option<Account<'info, TokenAccount>>,
+    /// Required only when a pool burns penalties.
+    #[account(mut, address = global_state.token_mint)]
+    pub token_mint: Option<Account<'info, Mint>>,
+    pub token_program: Program<'info, Token>,
 }
 
-#[account]
-pub struct Proposal {
-    pub proposal_id: u64,
-    pub proposer: Pubkey,
-    pub proposal_data: String,
-    pub snapshot_timestamp: i64,
-    pub vote_count: u64,
-    pub executed: bool,
+#[derive(Accounts)]
+pub struct ExtendLockup<'info> {
+    #[account(mut)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(
+        mut,
+        seeds = [b"stake_history", global_state.key().as_ref(), &global_state.stake_history_page.to_le_bytes()],
+        bump = stake_history.bump
+    )]
+    pub stake_history: Account<'info, StakeHistory>,
+    #[account(mut, has_one = global_state, address = user_stake.pool)]
+    pub from_pool: Account<'info, Pool>,
+    #[account(mut, has_one = global_state)]
+    pub to_pool: Account<'info, Pool>,
+    #[account(mut, has_one = owner)]
+    pub user_stake: Account<'info, UserStake>,
+    pub owner: Signer<'info>,
 }
 
 #[derive(Accounts)]
-pub struct Initialize<'info> {
-    #[account(init, payer = owner, space = 1200)]
-    pub global_state: Account<'info, GlobalState>,
-    pub token_mint: Account<'info, Mint>,
+pub struct LockVe<'info> {
     #[account(mut)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(
+        mut,
+        seeds = [b"stake_history", global_state.key().as_ref(), &global_state.stake_history_page.to_le_bytes()],
+        bump = stake_history.bump
+    )]
+    pub stake_history: Account<'info, StakeHistory>,
+    #[account(mut, has_one = global_state, address = user_stake.pool)]
+    pub pool: Account<'info, Pool>,
+    #[account(mut, seeds = [b"ve_state", global_state.key().as_ref()], bump = ve_state.bump)]
+    pub ve_state: Account<'info, VeState>,
+    #[account(mut, has_one = owner)]
+    pub user_stake: Account<'info, UserStake>,
     pub owner: Signer<'info>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
 }
 
 #[derive(Accounts)]
-pub struct UpdateParameters<'info> {
+#[instruction(target: Pubkey)]
+pub struct CreateGauge<'info> {
     #[account(mut, has_one = governance)]
     pub global_state: Account<'info, GlobalState>,
+    #[account(has_one = global_state)]
+    pub pool: Option<Account<'info, Pool>>,
+    #[account(
+        init,
+        payer = governance,
+        space = 160,
+        seeds = [b"gauge", global_state.key().as_ref(), target.as_ref()],
+        bump
+    )]
+    pub gauge: Account<'info, Gauge>,
     #[account(mut)]
     pub governance: Signer<'info>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct Stake<'info> {
-    #[account(mut)]
+pub struct VoteGauge<'info> {
     pub global_state: Account<'info, GlobalState>,
-    #[account(mut)]
+    #[account(mut, has_one = global_state)]
+    pub gauge: Account<'info, Gauge>,
+    #[account(mut, has_one = owner)]
     pub user_stake: Account<'info, UserStake>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CheckpointGauges<'info> {
     #[account(mut)]
-    pub user_token_account: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub staking_vault: Account<'info, TokenAccount>,
-    pub user: Signer<'info>,
-    pub token_program: Program<'info, Token>,
+    pub global_state: Account<'info, GlobalState>,
 }
 
 #[derive(Accounts)]
-pub struct Unstake<'info> {
+pub struct DelegateVotes<'info> {
+    #[account(mut, has_one = owner)]
+    pub user_stake: Account<'info, UserStake>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct PokePosition<'info> {
     #[account(mut)]
     pub global_state: Account<'info, GlobalState>,
+    #[account(
+        mut,
+        seeds = [b"stake_history", global_state.key().as_ref(), &global_state.stake_history_page.to_le_bytes()],
+        bump = stake_history.bump
+    )]
+    pub stake_history: Account<'info, StakeHistory>,
+    #[account(mut, has_one = global_state, address = user_stake.pool)]
+    pub pool: Account<'info, Pool>,
     #[account(mut)]
     pub user_stake: Account<'info, UserStake>,
+}
+
+#[derive(Accounts)]
+pub struct PokeLpPosition<'info> {
     #[account(mut)]
-    pub staking_vault: Account<'info, TokenAccount>,
+    pub global_state: Account<'info, GlobalState>,
+    #[account(
+        mut,
+        seeds = [b"stake_history", global_state.key().as_ref(), &global_state.stake_history_page.to_le_bytes()],
+        bump = stake_history.bump
+    )]
+    pub stake_history: Account<'info, StakeHistory>,
     #[account(mut)]
-    pub user_token_account: Account<'info, TokenAccount>,
-    /// CHECK: PDA authority.
-    #[account(seeds = [b"vault"], bump)]
-    pub vault_authority: AccountInfo<'info>,
+    pub lp_user_stake: Account<'info, LPUserStake>,
+}
+
+#[derive(Accounts)]
+pub struct CheckpointVe<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut, seeds = [b"ve_state", global_state.key().as_ref()], bump = ve_state.bump)]
+    pub ve_state: Account<'info, VeState>,
+}
+
+#[derive(Accounts)]
+pub struct MigratePosition<'info> {
     #[account(mut)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(
+        mut,
+        seeds = [b"stake_history", global_state.key().as_ref(), &global_state.stake_history_page.to_le_bytes()],
+        bump = stake_history.bump
+    )]
+    pub stake_history: Account<'info, StakeHistory>,
+    #[account(mut, has_one = global_state, address = user_stake.pool)]
+    pub from_pool: Account<'info, Pool>,
+    #[account(mut, has_one = global_state)]
+    pub to_pool: Account<'info, Pool>,
+    #[account(mut, has_one = owner)]
+    pub user_stake: Account<'info, UserStake>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct QuotePenalty<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(has_one = global_state, address = user_stake.pool)]
+    pub pool: Account<'info, Pool>,
+    pub user_stake: Account<'info, UserStake>,
+}
+
+#[derive(Accounts)]
+pub struct QuoteRewards<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(has_one = global_state, address = user_stake.pool)]
+    pub pool: Account<'info, Pool>,
+    #[account(seeds = [b"rebate_config", global_state.key().as_ref()], bump = rebate_config.bump)]
+    pub rebate_config: Account<'info, RebateConfig>,
+    pub user_stake: Account<'info, UserStake>,
+    #[account(address = global_state.reward_vault)]
     pub reward_vault: Account<'info, TokenAccount>,
-    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct QuoteLpRewards<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(seeds = [b"rebate_config", global_state.key().as_ref()], bump = rebate_config.bump)]
+    pub rebate_config: Account<'info, RebateConfig>,
+    pub lp_user_stake: Account<'info, LPUserStake>,
+    #[account(address = global_state.reward_vault)]
+    pub reward_vault: Account<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct QueryPosition<'info> {
+    pub user_stake: Account<'info, UserStake>,
+}
+
+#[derive(Accounts)]
+pub struct QueryStakeHistory<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(has_one = global_state)]
+    pub stake_history: Account<'info, StakeHistory>,
+    #[account(has_one = global_state)]
+    pub next_page: Option<Account<'info, StakeHistory>>,
+}
+
+#[derive(Accounts)]
+pub struct OpenStakeHistoryPage<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(
+        init,
+        payer = payer,
+        space = 1600,
+        seeds = [b"stake_history", global_state.key().as_ref(), &global_state.stake_history_page.to_le_bytes()],
+        bump
+    )]
+    pub stake_history: Account<'info, StakeHistory>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct OpenPosition<'info> {
+    pub pool: Account<'info, Pool>,
+    #[account(init, payer = owner, space = 1400)]
+    pub user_stake: Account<'info, UserStake>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
@@ -627,7 +4363,23 @@ pub struct DepositFee<'info> {
     pub depositor: Signer<'info>,
     #[account(mut)]
     pub depositor_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = global_state.reward_vault)]
+    pub reward_vault: Account<'info, TokenAccount>,
+    /// Required only when `treasury_fee_bps` is non-zero.
+    #[account(mut, address = global_state.treasury)]
+    pub treasury: Option<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct FundBonusBudget<'info> {
+    #[account(mut)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub funder: Signer<'info>,
     #[account(mut)]
+    pub funder_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = global_state.reward_vault)]
     pub reward_vault: Account<'info, TokenAccount>,
     pub token_program: Program<'info, Token>,
 }
@@ -636,9 +4388,19 @@ pub struct DepositFee<'info> {
 pub struct ClaimRewards<'info> {
     #[account(mut)]
     pub global_state: Account<'info, GlobalState>,
+    #[account(
+        mut,
+        seeds = [b"stake_history", global_state.key().as_ref(), &global_state.stake_history_page.to_le_bytes()],
+        bump = stake_history.bump
+    )]
+    pub stake_history: Account<'info, StakeHistory>,
+    #[account(mut, has_one = global_state, address = user_stake.pool)]
+    pub pool: Account<'info, Pool>,
+    #[account(seeds = [b"rebate_config", global_state.key().as_ref()], bump = rebate_config.bump)]
+    pub rebate_config: Account<'info, RebateConfig>,
     #[account(mut)]
     pub user_stake: Account<'info, UserStake>,
-    #[account(mut)]
+    #[account(mut, address = global_state.reward_vault)]
     pub reward_vault: Account<'info, TokenAccount>,
     #[account(mut)]
     pub user_reward_token_account: Account<'info, TokenAccount>,
@@ -652,11 +4414,21 @@ pub struct ClaimRewards<'info> {
 pub struct AutoCompound<'info> {
     #[account(mut)]
     pub global_state: Account<'info, GlobalState>,
+    #[account(
+        mut,
+        seeds = [b"stake_history", global_state.key().as_ref(), &global_state.stake_history_page.to_le_bytes()],
+        bump = stake_history.bump
+    )]
+    pub stake_history: Account<'info, StakeHistory>,
+    #[account(mut, has_one = global_state, address = user_stake.pool)]
+    pub pool: Account<'info, Pool>,
+    #[account(seeds = [b"rebate_config", global_state.key().as_ref()], bump = rebate_config.bump)]
+    pub rebate_config: Account<'info, RebateConfig>,
     #[account(mut)]
     pub user_stake: Account<'info, UserStake>,
-    #[account(mut)]
+    #[account(mut, address = global_state.reward_vault)]
     pub reward_vault: Account<'info, TokenAccount>,
-    #[account(mut)]
+    #[account(mut, address = global_state.staking_vault)]
     pub staking_vault: Account<'info, TokenAccount>,
     /// CHECK: PDA authority.
     #[account(seeds = [b"vault"], bump)]
@@ -670,6 +4442,12 @@ pub struct AutoCompound<'info> {
 pub struct LPStake<'info> {
     #[account(mut)]
     pub global_state: Account<'info, GlobalState>,
+    #[account(
+        mut,
+        seeds = [b"stake_history", global_state.key().as_ref(), &global_state.stake_history_page.to_le_bytes()],
+        bump = stake_history.bump
+    )]
+    pub stake_history: Account<'info, StakeHistory>,
     #[account(mut)]
     pub lp_user_stake: Account<'info, LPUserStake>,
     #[account(mut)]
@@ -684,6 +4462,12 @@ pub struct LPStake<'info> {
 pub struct LPUnstake<'info> {
     #[account(mut)]
     pub global_state: Account<'info, GlobalState>,
+    #[account(
+        mut,
+        seeds = [b"stake_history", global_state.key().as_ref(), &global_state.stake_history_page.to_le_bytes()],
+        bump = stake_history.bump
+    )]
+    pub stake_history: Account<'info, StakeHistory>,
     #[account(mut)]
     pub lp_user_stake: Account<'info, LPUserStake>,
     #[account(mut)]
@@ -700,9 +4484,17 @@ pub struct LPUnstake<'info> {
 pub struct LPClaimRewards<'info> {
     #[account(mut)]
     pub global_state: Account<'info, GlobalState>,
+    #[account(
+        mut,
+        seeds = [b"stake_history", global_state.key().as_ref(), &global_state.stake_history_page.to_le_bytes()],
+        bump = stake_history.bump
+    )]
+    pub stake_history: Account<'info, StakeHistory>,
+    #[account(seeds = [b"rebate_config", global_state.key().as_ref()], bump = rebate_config.bump)]
+    pub rebate_config: Account<'info, RebateConfig>,
     #[account(mut)]
     pub lp_user_stake: Account<'info, LPUserStake>,
-    #[account(mut)]
+    #[account(mut, address = global_state.reward_vault)]
     pub reward_vault: Account<'info, TokenAccount>,
     #[account(mut)]
     pub user_reward_token_account: Account<'info, TokenAccount>,
@@ -714,19 +4506,569 @@ pub struct LPClaimRewards<'info> {
 
 #[derive(Accounts)]
 pub struct SubmitProposal<'info> {
-    #[account(init, payer = proposer, space = 600)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut, seeds = [b"ve_state", global_state.key().as_ref()], bump = ve_state.bump)]
+    pub ve_state: Account<'info, VeState>,
+    #[account(init, payer = proposer, space = 800)]
     pub proposal: Account<'info, Proposal>,
     #[account(mut)]
     pub proposer: Signer<'info>,
+    /// Required only when `proposal_config.deposit` is non-zero.
+    #[account(mut)]
+    pub proposer_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(mut, address = global_state.proposal_deposit_vault)]
+    pub deposit_vault: Option<Account<'info, TokenAccount>>,
     pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
 }
 
 #[derive(Accounts)]
-pub struct VoteProposal<'info> {
+pub struct ExecuteProposal<'info> {
+    #[account(mut)]
+    pub proposal: Account<'info, Proposal>,
+}
+
+#[derive(Accounts)]
+pub struct TreasurySpend<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut, has_one = global_state)]
+    pub proposal: Account<'info, Proposal>,
+    /// CHECK: PDA authority.
+    #[account(seeds = [b"treasury", global_state.key().as_ref()], bump)]
+    pub treasury_authority: AccountInfo<'info>,
+    #[account(mut, seeds = [b"treasury_vault", global_state.key().as_ref()], bump)]
+    pub treasury_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub recipient: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct CancelProposal<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut, has_one = global_state, has_one = proposer, close = proposer)]
+    pub proposal: Account<'info, Proposal>,
+    #[account(mut)]
+    pub proposer: Signer<'info>,
+    /// Required only when the proposal holds a deposit.
     #[account(mut)]
+    pub proposer_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(mut, address = global_state.proposal_deposit_vault)]
+    pub deposit_vault: Option<Account<'info, TokenAccount>>,
+    /// CHECK: PDA authority.
+    #[account(seeds = [b"proposal_deposits", global_state.key().as_ref()], bump)]
+    pub deposit_authority: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct CloseProposal<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut, has_one = global_state, has_one = proposer, close = proposer)]
     pub proposal: Account<'info, Proposal>,
+    /// CHECK: receives the rent; checked against `proposal.proposer`.
+    #[account(mut)]
+    pub proposer: AccountInfo<'info>,
+    /// Required only when the deposit is refunded.
+    #[account(mut, constraint = proposer_token_account.owner == proposal.proposer)]
+    pub proposer_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(mut, address = global_state.proposal_deposit_vault)]
+    pub deposit_vault: Option<Account<'info, TokenAccount>>,
+    /// Required only when the deposit is slashed.
+    #[account(mut, address = global_state.treasury)]
+    pub treasury: Option<Account<'info, TokenAccount>>,
+    /// CHECK: PDA authority.
+    #[account(seeds = [b"proposal_deposits", global_state.key().as_ref()], bump)]
+    pub deposit_authority: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct VoteProposal<'info> {
     #[account(mut)]
+    pub proposal: Account<'info, Proposal>,
     pub user_stake: Account<'info, UserStake>,
+    #[account(
+        init,
+        payer = voter,
+        space = 120,
+        seeds = [b"vote", proposal.key().as_ref(), user_stake.key().as_ref()],
+        bump
+    )]
+    pub vote_record: Account<'info, VoteRecord>,
+    #[account(mut)]
     pub voter: Signer<'info>,
+    pub system_program: Program<'info, System>,
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A zeroed `GlobalState`: empty vectors, default enums, `None` options.
+    fn blank_state() -> GlobalState {
+        GlobalState::deserialize(&mut &[0u8; 1600][..]).unwrap()
+    }
+
+    #[test]
+    fn cliff_penalty_applies_until_lockup_ends() {
+        let curve = PenaltyCurve::Cliff;
+        assert_eq!(curve.penalty_bps(0, 100, 1_000), 1_000);
+        assert_eq!(curve.penalty_bps(99, 100, 1_000), 1_000);
+        assert_eq!(curve.penalty_bps(100, 100, 1_000), 0);
+        assert_eq!(curve.penalty_bps(-5, 100, 1_000), 1_000);
+    }
+
+    #[test]
+    fn linear_penalty_decays_to_zero() {
+        let curve = PenaltyCurve::Linear;
+        assert_eq!(curve.penalty_bps(0, 100, 1_000), 1_000);
+        assert_eq!(curve.penalty_bps(50, 100, 1_000), 500);
+        assert_eq!(curve.penalty_bps(99, 100, 1_000), 10);
+        assert_eq!(curve.penalty_bps(100, 100, 1_000), 0);
+        assert_eq!(curve.penalty_bps(1_000, 100, 1_000), 0);
+    }
+
+    #[test]
+    fn stepped_penalty_drops_at_step_boundaries() {
+        let curve = PenaltyCurve::Stepped { steps: 4 };
+        assert_eq!(curve.penalty_bps(0, 100, 1_000), 1_000);
+        assert_eq!(curve.penalty_bps(24, 100, 1_000), 1_000);
+        assert_eq!(curve.penalty_bps(25, 100, 1_000), 750);
+        assert_eq!(curve.penalty_bps(99, 100, 1_000), 250);
+        assert_eq!(curve.penalty_bps(100, 100, 1_000), 0);
+        // Zero steps behaves like a cliff.
+        assert_eq!(PenaltyCurve::Stepped { steps: 0 }.penalty_bps(99, 100, 1_000), 1_000);
+    }
+
+    #[test]
+    fn no_penalty_without_lockup() {
+        for curve in [PenaltyCurve::Cliff, PenaltyCurve::Linear, PenaltyCurve::Stepped { steps: 3 }] {
+            assert_eq!(curve.penalty_bps(0, 0, 1_000), 0);
+            assert_eq!(curve.penalty_bps(0, -1, 1_000), 0);
+        }
+    }
+
+    fn schedule_state(interpolate: bool) -> GlobalState {
+        let mut state = blank_state();
+        state.time_multiplier_schedule = vec![
+            MultiplierBreakpoint { min_duration: THIRTY_DAYS, multiplier: 120 },
+            MultiplierBreakpoint { min_duration: NINETY_DAYS, multiplier: 150 },
+        ];
+        state.interpolate_time_multiplier = interpolate;
+        state
+    }
+
+    #[test]
+    fn time_multiplier_steps_at_breakpoints() {
+        let state = schedule_state(false);
+        assert_eq!(time_multiplier(&state, 0), 100);
+        assert_eq!(time_multiplier(&state, THIRTY_DAYS - 1), 100);
+        assert_eq!(time_multiplier(&state, THIRTY_DAYS), 120);
+        assert_eq!(time_multiplier(&state, NINETY_DAYS - 1), 120);
+        assert_eq!(time_multiplier(&state, NINETY_DAYS), 150);
+        assert_eq!(time_multiplier(&state, 10 * NINETY_DAYS), 150);
+    }
+
+    #[test]
+    fn time_multiplier_interpolates_between_breakpoints() {
+        let state = schedule_state(true);
+        assert_eq!(time_multiplier(&state, THIRTY_DAYS - 1), 100);
+        assert_eq!(time_multiplier(&state, THIRTY_DAYS), 120);
+        assert_eq!(time_multiplier(&state, 60 * SECONDS_IN_DAY), 135);
+        assert_eq!(time_multiplier(&state, NINETY_DAYS - 1), 149);
+        assert_eq!(time_multiplier(&state, NINETY_DAYS), 150);
+        assert_eq!(time_multiplier(&state, 10 * NINETY_DAYS), 150);
+    }
+
+    #[test]
+    fn empty_time_multiplier_schedule_is_neutral() {
+        let state = blank_state();
+        assert_eq!(time_multiplier(&state, 0), 100);
+        assert_eq!(time_multiplier(&state, NINETY_DAYS), 100);
+    }
+
+    #[test]
+    fn multiplier_schedule_must_be_monotonic() {
+        let point = |min_duration, multiplier| MultiplierBreakpoint { min_duration, multiplier };
+        assert!(validate_multiplier_schedule(&[]).is_ok());
+        assert!(validate_multiplier_schedule(&[point(0, 100), point(10, 100), point(20, 130)]).is_ok());
+        assert!(validate_multiplier_schedule(&[point(10, 120), point(10, 130)]).is_err());
+        assert!(validate_multiplier_schedule(&[point(10, 130), point(20, 120)]).is_err());
+        assert!(validate_multiplier_schedule(&[point(-1, 100)]).is_err());
+        assert!(validate_multiplier_schedule(&[point(0, 0)]).is_err());
+        let too_long: Vec<_> = (0..=MAX_MULTIPLIER_BREAKPOINTS as i64).map(|i| point(i, 100)).collect();
+        assert!(validate_multiplier_schedule(&too_long).is_err());
+    }
+
+    fn ve_state(now: i64) -> VeState {
+        VeState {
+            global_state: Pubkey::default(),
+            bias: 0,
+            slope: 0,
+            last_checkpoint: now,
+            slope_changes: vec![0; VE_SCHEDULE_WEEKS],
+            bump: 0,
+        }
+    }
+
+    /// Slope worth one unit of voting power per second of remaining lock.
+    const UNIT_SLOPE: u128 = VE_PRECISION;
+
+    #[test]
+    fn ve_power_decays_and_expires_at_lock_end() {
+        let now = 1_000 * WEEK + 3 * SECONDS_IN_DAY;
+        let end = 1_002 * WEEK;
+        let mut ve = ve_state(now);
+        ve.replace_lock(0, 0, UNIT_SLOPE, end, now).unwrap();
+        assert_eq!(ve.total_power(), (end - now) as u64);
+        assert_eq!(ve.total_power_at(1_001 * WEEK).unwrap(), WEEK as u64);
+
+        ve.checkpoint(end - 1).unwrap();
+        assert_eq!(ve.total_power(), 1);
+        assert_eq!(ve.slope, UNIT_SLOPE);
+
+        ve.checkpoint(end).unwrap();
+        assert_eq!(ve.total_power(), 0);
+        assert_eq!(ve.slope, 0);
+        assert_eq!(ve.slope_changes[VeState::slot(end)], 0);
+
+        ve.checkpoint(end + 5 * WEEK).unwrap();
+        assert_eq!(ve.total_power(), 0);
+    }
+
+    #[test]
+    fn ve_slope_expires_across_ring_wrap() {
+        // Start five weeks before the slope ring wraps back to slot 0.
+        let ring = VE_SCHEDULE_WEEKS as i64;
+        let now = (3 * ring + ring - 5) * WEEK;
+        let early_end = now + 2 * WEEK;
+        let late_end = now + 10 * WEEK;
+        assert!(VeState::slot(late_end) < VeState::slot(now));
+
+        let mut ve = ve_state(now);
+        ve.replace_lock(0, 0, UNIT_SLOPE, early_end, now).unwrap();
+        ve.replace_lock(0, 0, 2 * UNIT_SLOPE, late_end, now).unwrap();
+        assert_eq!(ve.total_power(), (2 * WEEK + 20 * WEEK) as u64);
+
+        ve.checkpoint(early_end).unwrap();
+        assert_eq!(ve.slope, 2 * UNIT_SLOPE);
+        assert_eq!(ve.total_power(), 16 * WEEK as u64);
+
+        ve.checkpoint(late_end - WEEK).unwrap();
+        assert_eq!(ve.total_power(), 2 * WEEK as u64);
+
+        ve.checkpoint(late_end).unwrap();
+        assert_eq!(ve.slope, 0);
+        assert_eq!(ve.total_power(), 0);
+        assert!(ve.slope_changes.iter().all(|change| *change == 0));
+    }
+
+    #[test]
+    fn ve_lock_can_span_almost_the_whole_ring() {
+        let now = 500 * WEEK;
+        let end = now + MAX_VE_LOCK / WEEK * WEEK;
+        let mut ve = ve_state(now);
+        ve.replace_lock(0, 0, UNIT_SLOPE, end, now).unwrap();
+
+        // The lock's slot must not be reached, and cleared, before the lock ends.
+        ve.checkpoint(end - 1).unwrap();
+        assert_eq!(ve.slope, UNIT_SLOPE);
+        assert_eq!(ve.total_power(), 1);
+        ve.checkpoint(end).unwrap();
+        assert_eq!(ve.slope, 0);
+        assert_eq!(ve.total_power(), 0);
+    }
+
+    #[test]
+    fn ve_replace_lock_moves_the_expiring_slope() {
+        let now = 200 * WEEK;
+        let old_end = now + 4 * WEEK;
+        let new_end = now + 8 * WEEK;
+        let mut ve = ve_state(now);
+        ve.replace_lock(0, 0, UNIT_SLOPE, old_end, now).unwrap();
+
+        ve.checkpoint(now + WEEK).unwrap();
+        let now = now + WEEK;
+        ve.replace_lock(UNIT_SLOPE, old_end, 3 * UNIT_SLOPE, new_end, now).unwrap();
+        assert_eq!(ve.slope, 3 * UNIT_SLOPE);
+        assert_eq!(ve.total_power(), (3 * (new_end - now)) as u64);
+        assert_eq!(ve.slope_changes[VeState::slot(old_end)], 0);
+        assert_eq!(ve.slope_changes[VeState::slot(new_end)], 3 * UNIT_SLOPE);
+
+        // The old end passes without dropping the replaced slope.
+        ve.checkpoint(old_end).unwrap();
+        assert_eq!(ve.slope, 3 * UNIT_SLOPE);
+        ve.checkpoint(new_end).unwrap();
+        assert_eq!(ve.slope, 0);
+        assert_eq!(ve.total_power(), 0);
+    }
+
+    #[test]
+    fn ve_power_at_rejects_past_timestamps() {
+        let now = 10 * WEEK;
+        let mut ve = ve_state(now);
+        ve.replace_lock(0, 0, UNIT_SLOPE, now + WEEK, now).unwrap();
+        assert!(ve.total_power_at(now - 1).is_err());
+        assert_eq!(ve.total_power_at(now).unwrap(), WEEK as u64);
+    }
+
+    #[test]
+    fn fund_claim_pays_bonus_from_budget() {
+        let mut state = blank_state();
+        state.total_reward_liabilities = 1_000;
+        state.bonus_budget = 500;
+        assert_eq!(fund_claim(&mut state, 400, 600).unwrap(), 600);
+        assert_eq!(state.total_reward_liabilities, 600);
+        assert_eq!(state.bonus_budget, 300);
+    }
+
+    #[test]
+    fn fund_claim_caps_bonus_at_remaining_budget() {
+        let mut state = blank_state();
+        state.total_reward_liabilities = 1_000;
+        state.bonus_budget = 50;
+        assert_eq!(fund_claim(&mut state, 400, 600).unwrap(), 450);
+        assert_eq!(state.total_reward_liabilities, 600);
+        assert_eq!(state.bonus_budget, 0);
+
+        // With the budget exhausted only the base allocation is paid.
+        assert_eq!(fund_claim(&mut state, 100, 150).unwrap(), 100);
+        assert_eq!(state.bonus_budget, 0);
+    }
+
+    #[test]
+    fn fund_claim_returns_haircut_to_budget() {
+        let mut state = blank_state();
+        state.total_reward_liabilities = 1_000;
+        state.bonus_budget = 10;
+        assert_eq!(fund_claim(&mut state, 400, 300).unwrap(), 300);
+        assert_eq!(state.total_reward_liabilities, 600);
+        assert_eq!(state.bonus_budget, 110);
+    }
+
+    #[test]
+    fn fund_claim_saturates_liabilities() {
+        let mut state = blank_state();
+        state.total_reward_liabilities = 100;
+        assert_eq!(fund_claim(&mut state, 400, 400).unwrap(), 400);
+        assert_eq!(state.total_reward_liabilities, 0);
+    }
+
+    #[test]
+    fn pay_claim_pays_in_full_when_vault_covers_it() {
+        let mut state = blank_state();
+        state.insurance_fund = 100;
+        let mut owed = 0;
+        assert_eq!(pay_claim(&mut state, &mut owed, 500, 1_000).unwrap(), 500);
+        assert_eq!(owed, 0);
+        assert_eq!(state.total_owed, 0);
+    }
+
+    #[test]
+    fn pay_claim_records_shortfall_as_owed() {
+        let mut state = blank_state();
+        state.insurance_fund = 100;
+        state.owed_reserve = 50;
+        state.total_owed = 50;
+        let mut owed = 0;
+        // Only 1_000 - 100 insurance - 50 reserved for other IOUs is distributable.
+        assert_eq!(pay_claim(&mut state, &mut owed, 1_000, 1_000).unwrap(), 850);
+        assert_eq!(owed, 150);
+        assert_eq!(state.total_owed, 200);
+        assert_eq!(state.owed_reserve, 50);
+    }
+
+    #[test]
+    fn pay_claim_repays_owed_from_reserve_first() {
+        let mut state = blank_state();
+        state.insurance_fund = 100;
+        state.owed_reserve = 300;
+        state.total_owed = 500;
+        let mut owed = 200;
+        // The 200 repaid comes out of the reserve; 1_000 - 100 - 100 - 200 is left for the new reward.
+        assert_eq!(pay_claim(&mut state, &mut owed, 700, 1_000).unwrap(), 800);
+        assert_eq!(owed, 100);
+        assert_eq!(state.owed_reserve, 100);
+        assert_eq!(state.total_owed, 400);
+    }
+
+    #[test]
+    fn pay_claim_repays_partially_when_reserve_is_short() {
+        let mut state = blank_state();
+        state.owed_reserve = 30;
+        state.total_owed = 100;
+        let mut owed = 100;
+        assert_eq!(pay_claim(&mut state, &mut owed, 0, 30).unwrap(), 30);
+        assert_eq!(owed, 70);
+        assert_eq!(state.owed_reserve, 0);
+        assert_eq!(state.total_owed, 70);
+    }
+
+    #[test]
+    fn pay_claim_with_empty_vault_owes_everything() {
+        let mut state = blank_state();
+        state.insurance_fund = 100;
+        let mut owed = 0;
+        assert_eq!(pay_claim(&mut state, &mut owed, 250, 80).unwrap(), 0);
+        assert_eq!(owed, 250);
+        assert_eq!(state.total_owed, 250);
+    }
+
+    fn lot(amount: u64, timestamp: i64, pool: Pubkey) -> StakeLot {
+        StakeLot { amount, timestamp, pool }
+    }
+
+    fn blank_stake() -> UserStake {
+        UserStake::deserialize(&mut &[0u8; 2048][..]).unwrap()
+    }
+
+    #[test]
+    fn full_lot_list_merges_the_newest_lots() {
+        let pool = Pubkey::new_unique();
+        let mut user = blank_stake();
+        for i in 0..MAX_STAKE_LOTS as i64 {
+            user.push_lot(lot(100, i, pool)).unwrap();
+        }
+        user.push_lot(lot(1, 50, pool)).unwrap();
+        assert_eq!(user.lots.len(), MAX_STAKE_LOTS);
+        // The old principal keeps its deposit time; only the newest lot absorbs the top-up.
+        assert_eq!(user.lots[0].amount, 100);
+        assert_eq!(user.lots[0].timestamp, 0);
+        let newest = user.lots.last().unwrap();
+        assert_eq!(newest.amount, 101);
+        assert_eq!(newest.timestamp, 50);
+    }
+
+    #[test]
+    fn lot_merge_never_moves_migrated_stake_between_pools() {
+        let retired = Pubkey::new_unique();
+        let active = Pubkey::new_unique();
+        let mut user = blank_stake();
+        for i in 0..MAX_STAKE_LOTS as i64 {
+            user.push_lot(lot(100, i, retired)).unwrap();
+        }
+        user.push_lot(lot(5, 50, active)).unwrap();
+        assert_eq!(user.lots.len(), MAX_STAKE_LOTS);
+        assert!(user.lots[..MAX_STAKE_LOTS - 1].iter().all(|l| l.pool == retired));
+        assert_eq!(user.lots[MAX_STAKE_LOTS - 2].amount, 200);
+        assert_eq!(user.lots[MAX_STAKE_LOTS - 1].pool, active);
+
+        let info = PoolInfo { lockup_period: 100, ..Default::default() };
+        let total = 100 * MAX_STAKE_LOTS as u64;
+        assert_eq!(user.quote_penalty(total, 60, active, &info, 1_000).unwrap(), 0);
+        assert_eq!(user.quote_penalty(total + 5, 60, active, &info, 1_000).unwrap(), 0);
+    }
+
+    #[test]
+    fn lot_merge_fails_when_no_pool_pair_is_left() {
+        let pools: Vec<_> = (0..2).map(|_| Pubkey::new_unique()).collect();
+        let mut user = blank_stake();
+        for i in 0..MAX_STAKE_LOTS {
+            user.push_lot(lot(1, i as i64, pools[i % 2])).unwrap();
+        }
+        assert!(user.push_lot(lot(1, 50, pools[MAX_STAKE_LOTS % 2])).is_err());
+    }
+
+    #[test]
+    fn dust_top_up_barely_moves_warm_up() {
+        let end = weighted_stake_timestamp(1_000_000, 1_000, 1, 2_000).unwrap();
+        assert_eq!(end, 1_000);
+        assert_eq!(weighted_stake_timestamp(0, 0, 500, 2_000).unwrap(), 2_000);
+        assert_eq!(weighted_stake_timestamp(500, 1_000, 500, 2_000).unwrap(), 1_500);
+    }
+
+    #[test]
+    fn stream_carries_sub_token_remainder() {
+        let mut state = blank_state();
+        state.total_reward_shares = 1;
+        // Half a token per second.
+        state.reward_rate = REWARD_MULTIPLIER as u128 / 2;
+        state.period_finish = 100;
+        for now in 1..=10 {
+            update_reward_accrual(&mut state, now).unwrap();
+        }
+        assert_eq!(state.total_reward_liabilities, 5);
+        assert_eq!(state.reward_remainder, 0);
+        update_reward_accrual(&mut state, 11).unwrap();
+        assert_eq!(state.total_reward_liabilities, 5);
+        assert_eq!(state.reward_remainder, REWARD_MULTIPLIER as u128 / 2);
+    }
+
+    #[test]
+    fn allocation_over_a_tiny_base_does_not_overflow() {
+        let mut state = blank_state();
+        state.total_reward_shares = 1;
+        allocate_rewards(&mut state, u64::MAX).unwrap();
+        assert_eq!(state.acc_reward_per_share, u64::MAX as u128 * REWARD_MULTIPLIER as u128);
+        assert_eq!(accrued_rewards(1, state.acc_reward_per_share).unwrap(), u64::MAX);
+    }
+
+    fn history(page: u64) -> StakeHistory {
+        StakeHistory { global_state: Pubkey::default(), page, checkpoints: Vec::new(), bump: 0 }
+    }
+
+    #[test]
+    fn stake_history_skips_unchanged_totals_and_merges_same_second() {
+        let mut state = blank_state();
+        let mut page = history(0);
+        state.total_staked = 10;
+        page.record(&mut state, 1).unwrap();
+        page.record(&mut state, 2).unwrap();
+        assert_eq!(page.checkpoints.len(), 1);
+        state.total_staked = 20;
+        page.record(&mut state, 3).unwrap();
+        state.total_staked = 30;
+        page.record(&mut state, 3).unwrap();
+        assert_eq!(page.checkpoints.len(), 2);
+        assert_eq!(page.checkpoint_at(2).unwrap().total_staked, 10);
+        assert_eq!(page.checkpoint_at(3).unwrap().total_staked, 30);
+        assert!(page.checkpoint_at(0).is_none());
+    }
+
+    #[test]
+    fn stake_history_moves_to_the_next_page_when_full() {
+        let mut state = blank_state();
+        let mut page = history(0);
+        for t in 0..STAKE_HISTORY_PAGE_LEN as i64 {
+            state.total_staked = t as u64 + 1;
+            page.record(&mut state, t).unwrap();
+        }
+        assert_eq!(state.stake_history_page, 1);
+        // Nothing is ever dropped; a further write needs the next page.
+        assert_eq!(page.checkpoint_at(0).unwrap().total_staked, 1);
+        state.total_staked = 0;
+        assert!(page.record(&mut state, 1_000).is_err());
+        let mut next = history(1);
+        next.record(&mut state, 1_000).unwrap();
+        assert_eq!(next.checkpoint_at(1_000).unwrap().total_staked, 0);
+    }
+
+    #[test]
+    fn full_position_history_keeps_vote_checkpoints() {
+        let mut user = blank_stake();
+        user.ve_slope = UNIT_SLOPE;
+        user.ve_lock_end = 1_000;
+        user.write_checkpoint(10);
+        // Balance-only changes, e.g. compounding, cannot push the lock out.
+        for t in 0..2 * MAX_POSITION_CHECKPOINTS as i64 {
+            user.staked_amount = t as u64 + 1;
+            user.write_checkpoint(20 + t);
+        }
+        assert_eq!(user.checkpoints.len(), MAX_POSITION_CHECKPOINTS);
+        let first = user.checkpoint_at(15).unwrap();
+        assert_eq!(first.timestamp, 10);
+        assert_eq!(first.voting_power_at(15), 985);
+        assert_eq!(user.checkpoint_at(100).unwrap().staked_amount, 2 * MAX_POSITION_CHECKPOINTS as u64);
+    }
+
+    #[test]
+    fn default_rebate_tiers_are_valid() {
+        let tiers = RebateConfig::default_tiers();
+        assert!(validate_rebate_tiers(&tiers).is_ok());
+        let config = RebateConfig { global_state: Pubkey::default(), tiers, bump: 0 };
+        assert_eq!(config.rebate(10_000), 0);
+        assert_eq!(config.rebate(10_001), 5);
+        assert_eq!(config.rebate(2_000_000), 15);
+    }
+}